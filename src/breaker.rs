@@ -0,0 +1,198 @@
+//! Circuit breaker failing connection creation fast while the brokers are
+//! down.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::CircuitBreakerConfig;
+
+/// State of the circuit breaker of a [`Manager`](crate::Manager), see
+/// [`CircuitBreakerConfig`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CircuitState {
+    /// Connections are opened as usual.
+    Closed,
+    /// Opening connections failed too often, creating connections fails
+    /// right away with [`Error::CircuitOpen`](crate::Error::CircuitOpen).
+    Open,
+    /// The reset timeout passed, the next attempt to open a connection
+    /// probes whether the brokers are back.
+    HalfOpen,
+}
+
+impl CircuitState {
+    /// Returns a short, stable name of this state for metrics and logs.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub(crate) const fn label(self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::Open => "open",
+            Self::HalfOpen => "half_open",
+        }
+    }
+}
+
+pub(crate) struct CircuitBreaker {
+    failure_threshold: u32,
+    reset_timeout: Duration,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Number of consecutive failures.
+    failures: u32,
+    /// When the circuit was last opened, unset while it is closed.
+    opened_at: Option<Instant>,
+    /// When the probe of the half-open circuit started.
+    ///
+    /// A probe which didn't report back within the reset timeout, e.g.
+    /// because it was cancelled, doesn't block further probes.
+    probe_started_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(config: &CircuitBreakerConfig) -> Self {
+        Self {
+            failure_threshold: config.failure_threshold,
+            reset_timeout: config.reset_timeout,
+            inner: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn state(&self) -> CircuitState {
+        match self.inner.lock().unwrap().opened_at {
+            None => CircuitState::Closed,
+            Some(at) if at.elapsed() < self.reset_timeout => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Returns whether a connection may be opened now.
+    ///
+    /// While the circuit is half-open only one probe is let through at a
+    /// time.
+    pub(crate) fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(opened_at) = inner.opened_at else {
+            return true;
+        };
+        if opened_at.elapsed() < self.reset_timeout {
+            return false;
+        }
+        if inner
+            .probe_started_at
+            .is_some_and(|at| at.elapsed() < self.reset_timeout)
+        {
+            return false;
+        }
+        inner.probe_started_at = Some(Instant::now());
+        true
+    }
+
+    /// Records whether opening a connection succeeded, closing the circuit
+    /// on success and opening it after too many consecutive failures.
+    pub(crate) fn record(&self, success: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if success {
+            #[cfg(feature = "tracing")]
+            if inner.opened_at.is_some() {
+                tracing::info!("circuit breaker closed");
+            }
+            *inner = Inner::default();
+            return;
+        }
+        inner.failures = inner.failures.saturating_add(1);
+        if inner.opened_at.is_some() || inner.failures >= self.failure_threshold {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                failures = inner.failures,
+                reset_timeout = ?self.reset_timeout,
+                "circuit breaker opened"
+            );
+            inner.opened_at = Some(Instant::now());
+            inner.probe_started_at = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESET_TIMEOUT: Duration = Duration::from_millis(50);
+
+    fn breaker(failure_threshold: u32) -> CircuitBreaker {
+        CircuitBreaker::new(&CircuitBreakerConfig {
+            failure_threshold,
+            reset_timeout: RESET_TIMEOUT,
+        })
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = breaker(3);
+        breaker.record(false);
+        breaker.record(false);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.allow());
+        breaker.record(false);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let breaker = breaker(2);
+        breaker.record(false);
+        breaker.record(true);
+        breaker.record(false);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn half_open_lets_one_probe_through() {
+        let breaker = breaker(1);
+        breaker.record(false);
+        std::thread::sleep(RESET_TIMEOUT);
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn successful_probe_closes_circuit() {
+        let breaker = breaker(1);
+        breaker.record(false);
+        std::thread::sleep(RESET_TIMEOUT);
+        assert!(breaker.allow());
+        breaker.record(true);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn failed_probe_reopens_circuit() {
+        let breaker = breaker(3);
+        for _ in 0..3 {
+            breaker.record(false);
+        }
+        std::thread::sleep(RESET_TIMEOUT);
+        assert!(breaker.allow());
+        breaker.record(false);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn unfinished_probe_expires() {
+        let breaker = breaker(1);
+        breaker.record(false);
+        std::thread::sleep(RESET_TIMEOUT);
+        assert!(breaker.allow());
+        std::thread::sleep(RESET_TIMEOUT);
+        assert!(breaker.allow());
+    }
+}
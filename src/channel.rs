@@ -0,0 +1,323 @@
+//! Pooling of [`amqprs`] channels on top of the connection [`Pool`].
+//!
+//! Opening a channel costs a round-trip to the broker, so instead of calling
+//! [`Connection::open_channel()`][1] for every unit of work, a [`ChannelPool`]
+//! keeps channels open and multiplexes up to
+//! [`ChannelConfig::channels_per_connection`] of them over every connection it
+//! checks out of the connection [`Pool`]. A connection is handed back to the
+//! connection [`Pool`] once the last channel opened on it is dropped.
+//!
+//! # Example
+//!
+//! ```rs
+//! use deadpool_amqprs::{channel::ChannelConfig, Config};
+//! use amqprs::connection::OpenConnectionArguments;
+//!
+//! let pool = Config::new_with_con_args(OpenConnectionArguments::default()).create_pool();
+//! let channels = ChannelConfig::default().create_pool(pool);
+//!
+//! let channel = channels.get().await.unwrap();
+//!
+//! // Do stuff with `channel`.
+//! ```
+//!
+//! [1]: amqprs::connection::Connection::open_channel
+
+use std::{
+    fmt,
+    ops::Deref,
+    sync::{Arc, Mutex, Weak},
+};
+
+use amqprs::channel::Channel;
+use deadpool::managed::{self, RecycleError, RecycleResult};
+use deadpool::{async_trait, Runtime};
+
+use crate::{ConfigError, Metrics, Object, Pool, PoolConfig, PoolError, PoolExt};
+
+/// Default value of [`ChannelConfig::channels_per_connection`].
+pub const DEFAULT_CHANNELS_PER_CONNECTION: usize = 8;
+
+/// Type alias for using [`deadpool::managed::Pool`] with [`ChannelManager`].
+pub type ChannelPool = managed::Pool<ChannelManager>;
+
+/// Type alias for using [`deadpool::managed::PoolBuilder`] with [`ChannelManager`].
+pub type ChannelPoolBuilder = managed::PoolBuilder<ChannelManager>;
+
+/// Type alias for using [`deadpool::managed::PoolError`] with [`ChannelManager`].
+pub type ChannelPoolError = managed::PoolError<ChannelError>;
+
+/// Type alias for using [`deadpool::managed::Object`] with [`ChannelManager`].
+pub type ChannelObject = managed::Object<ChannelManager>;
+
+/// Configuration object for a [`ChannelPool`].
+#[derive(Clone, Copy, Debug)]
+pub struct ChannelConfig {
+    /// Maximum number of pooled channels opened on a single connection.
+    pub channels_per_connection: usize,
+    /// The [`PoolConfig`] passed to deadpool.
+    pub pool_config: Option<PoolConfig>,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self::new(DEFAULT_CHANNELS_PER_CONNECTION, None)
+    }
+}
+
+impl ChannelConfig {
+    /// Creates a new channel config with the given number of channels per
+    /// connection and optionally [`PoolConfig`].
+    #[must_use]
+    pub const fn new(channels_per_connection: usize, pool_config: Option<PoolConfig>) -> Self {
+        Self {
+            channels_per_connection,
+            pool_config,
+        }
+    }
+
+    /// Creates a new [`ChannelPool`] opening its channels on connections from
+    /// `pool`.
+    #[must_use]
+    pub fn create_pool(&self, pool: Pool) -> ChannelPool {
+        self.builder(pool)
+            .build()
+            .expect("`ChannelPoolBuilder::build` errored when it shouldn't")
+    }
+
+    /// Like [`ChannelConfig::create_pool()`], but first checks that
+    /// [`ChannelConfig::channels_per_connection`] is at least `1` and fits
+    /// into the [`ConnectionConfig::channel_max`](crate::ConnectionConfig::channel_max)
+    /// of every endpoint of `pool`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ConfigError`] if this config doesn't fit `pool`.
+    pub fn try_create_pool(&self, pool: Pool) -> Result<ChannelPool, ConfigError> {
+        self.validate(&pool)?;
+        Ok(self.create_pool(pool))
+    }
+
+    /// Checks that this config can be used with connections from `pool`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ConfigError`] for the first invalid setting.
+    pub fn validate(&self, pool: &Pool) -> Result<(), ConfigError> {
+        let max_size = self.pool_config.unwrap_or_default().max_size;
+        if max_size == 0 {
+            return Err(ConfigError::InvalidPoolSize(max_size));
+        }
+        if self.channels_per_connection == 0 {
+            return Err(ConfigError::InvalidChannelsPerConnection(0));
+        }
+        match pool.manager().channel_max() {
+            Some(channel_max) if self.channels_per_connection > usize::from(channel_max) => {
+                Err(ConfigError::ChannelMaxExceeded {
+                    channels_per_connection: self.channels_per_connection,
+                    channel_max,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns a [`ChannelPoolBuilder`] opening its channels on connections
+    /// from `pool`.
+    pub fn builder(&self, pool: Pool) -> ChannelPoolBuilder {
+        ChannelPool::builder(ChannelManager::new(pool, self.channels_per_connection))
+            .config(self.pool_config.unwrap_or_default())
+            .runtime(Runtime::Tokio1)
+    }
+}
+
+/// Error returned by [`ChannelManager`] when a channel couldn't be opened.
+#[derive(Debug)]
+pub enum ChannelError {
+    /// No connection could be checked out of the connection [`Pool`].
+    Pool(PoolError),
+    /// The broker refused to open the channel.
+    Backend(amqprs::error::Error),
+}
+
+impl fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pool(e) => write!(f, "Connection pool error: {e}"),
+            Self::Backend(e) => write!(f, "Channel error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ChannelError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Pool(e) => Some(e),
+            Self::Backend(e) => Some(e),
+        }
+    }
+}
+
+impl From<PoolError> for ChannelError {
+    fn from(e: PoolError) -> Self {
+        Self::Pool(e)
+    }
+}
+
+impl From<amqprs::error::Error> for ChannelError {
+    fn from(e: amqprs::error::Error) -> Self {
+        Self::Backend(e)
+    }
+}
+
+/// [`Channel`] handed out by a [`ChannelPool`].
+///
+/// Holds on to the pooled connection it was opened on, which is returned to
+/// the connection [`Pool`] once every channel sharing it has been dropped.
+pub struct PooledChannel {
+    /// Only `None` while being dropped.
+    channel: Option<Channel>,
+    connection: Arc<Object>,
+}
+
+impl PooledChannel {
+    /// Opens a new channel on `connection`.
+    pub(crate) async fn open(connection: Arc<Object>) -> Result<Self, amqprs::error::Error> {
+        let channel = connection.open_channel(None).await?;
+        Ok(Self {
+            channel: Some(channel),
+            connection,
+        })
+    }
+
+    /// Returns the connection this channel was opened on.
+    #[must_use]
+    pub fn connection(&self) -> &amqprs::connection::Connection {
+        &self.connection
+    }
+}
+
+impl Deref for PooledChannel {
+    type Target = Channel;
+
+    fn deref(&self) -> &Channel {
+        self.channel
+            .as_ref()
+            .expect("`PooledChannel` used after being dropped")
+    }
+}
+
+impl Drop for PooledChannel {
+    fn drop(&mut self) {
+        let Some(channel) = self.channel.take() else {
+            return;
+        };
+        if !channel.is_open() {
+            return;
+        }
+        // Keep the connection checked out until the channel is closed.
+        let connection = Arc::clone(&self.connection);
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                let _ = channel.close().await;
+                drop(connection);
+            });
+        }
+    }
+}
+
+/// [`Manager`] for creating and recycling [`amqprs`] channels.
+///
+/// [`Manager`]: managed::Manager
+pub struct ChannelManager {
+    pool: Pool,
+    channels_per_connection: usize,
+    connections: Mutex<Vec<Weak<Object>>>,
+    /// Held while checking out a new connection, so concurrent callers share
+    /// it instead of each checking out their own.
+    checkout: tokio::sync::Mutex<()>,
+}
+
+impl ChannelManager {
+    /// Creates a new [`ChannelManager`] opening at most
+    /// `channels_per_connection` channels on each connection from `pool`.
+    #[must_use]
+    pub fn new(pool: Pool, channels_per_connection: usize) -> Self {
+        Self {
+            pool,
+            channels_per_connection: channels_per_connection.max(1),
+            connections: Mutex::new(Vec::new()),
+            checkout: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Returns the connection [`Pool`] channels are opened on.
+    #[must_use]
+    pub const fn connection_pool(&self) -> &Pool {
+        &self.pool
+    }
+
+    /// Returns a connection with room for another channel, checking out a
+    /// new one if no shared connection has any.
+    async fn connection(&self) -> Result<Arc<Object>, PoolError> {
+        if let Some(connection) = self.shared_connection() {
+            return Ok(connection);
+        }
+        let _checkout = self.checkout.lock().await;
+        // Another caller might have checked out a connection meanwhile.
+        if let Some(connection) = self.shared_connection() {
+            return Ok(connection);
+        }
+        let connection = Arc::new(self.pool.checkout().await?);
+        self.connections
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(&connection));
+        Ok(connection)
+    }
+
+    /// Returns an open connection which still has room for another channel.
+    ///
+    /// The returned [`Arc`] is counted as a channel, so concurrent callers
+    /// never overshoot `channels_per_connection`.
+    fn shared_connection(&self) -> Option<Arc<Object>> {
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|connection| connection.strong_count() > 0);
+        connections
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find(|connection| {
+                connection.is_usable()
+                    && Arc::strong_count(connection) <= self.channels_per_connection
+            })
+    }
+}
+
+impl fmt::Debug for ChannelManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChannelManager")
+            .field("pool", &self.pool.status())
+            .field("channels_per_connection", &self.channels_per_connection)
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl managed::Manager for ChannelManager {
+    type Type = PooledChannel;
+    type Error = ChannelError;
+
+    async fn create(&self) -> Result<Self::Type, Self::Error> {
+        Ok(PooledChannel::open(self.connection().await?).await?)
+    }
+
+    async fn recycle(&self, channel: &mut Self::Type, _: &Metrics) -> RecycleResult<Self::Error> {
+        if !channel.connection.is_usable() {
+            return Err(RecycleError::StaticMessage("Connection closed."));
+        }
+        if !channel.is_open() {
+            return Err(RecycleError::StaticMessage("Channel closed."));
+        }
+        Ok(())
+    }
+}
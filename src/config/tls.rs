@@ -0,0 +1,172 @@
+//! TLS configuration of connections to `amqps` brokers.
+
+use std::{
+    fmt, io,
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+};
+
+use amqprs::tls::TlsAdaptor;
+use tokio_rustls::{
+    rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore},
+    TlsConnector,
+};
+
+/// PEM encoded certificates or private key, either read from a file or given
+/// in memory.
+#[derive(Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum PemSource {
+    /// Path of a PEM file, read every time a connection is opened.
+    Path(PathBuf),
+    /// PEM encoded contents.
+    Pem(String),
+}
+
+impl PemSource {
+    fn read(&self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Path(path) => std::fs::read(path),
+            Self::Pem(pem) => Ok(pem.as_bytes().to_vec()),
+        }
+    }
+}
+
+impl fmt::Debug for PemSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Path(path) => f.debug_tuple("Path").field(path).finish(),
+            // Might be a private key.
+            Self::Pem(_) => f.debug_tuple("Pem").field(&"<redacted>").finish(),
+        }
+    }
+}
+
+/// Client certificate and private key presented to the broker for mutual TLS.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct ClientIdentity {
+    /// Client certificate, followed by any intermediate certificates.
+    pub certificate_chain: PemSource,
+    /// Private key of the client certificate.
+    pub private_key: PemSource,
+}
+
+/// TLS configuration of connections.
+///
+/// # Example
+///
+/// ```toml
+/// [rabbitmq.tls]
+/// ca_certificates = { path = "/etc/rabbitmq/ca.pem" }
+/// domain = "rabbitmq.internal"
+///
+/// [rabbitmq.tls.client_identity]
+/// certificate_chain = { path = "/etc/rabbitmq/client.pem" }
+/// private_key = { path = "/etc/rabbitmq/client.key" }
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct TlsConfig {
+    /// CA certificates the broker's certificate is verified against.
+    ///
+    /// Default: the Mozilla root certificates bundled by `webpki-roots`
+    pub ca_certificates: Option<PemSource>,
+    /// Client identity presented to the broker.
+    ///
+    /// Default: no client authentication
+    pub client_identity: Option<ClientIdentity>,
+    /// Server name sent via SNI and verified against the broker's certificate.
+    ///
+    /// Default: the host of the endpoint
+    pub domain: Option<String>,
+}
+
+impl TlsConfig {
+    /// Builds the [`TlsAdaptor`] of a connection to `host`.
+    ///
+    /// Reads [`PemSource::Path`]s from disk, so call this off the runtime.
+    pub(crate) fn adaptor(&self, host: &str) -> io::Result<TlsAdaptor> {
+        let domain = self.domain.as_deref().unwrap_or(host).to_owned();
+
+        let roots = match &self.ca_certificates {
+            Some(ca_certificates) => {
+                let mut roots = RootCertStore::empty();
+                for certificate in certificates(ca_certificates)? {
+                    roots
+                        .add(&certificate)
+                        .map_err(|e| invalid_data(e.to_string()))?;
+                }
+                roots
+            }
+            None => webpki_roots().clone(),
+        };
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let config = match &self.client_identity {
+            Some(identity) => builder
+                .with_single_cert(
+                    certificates(&identity.certificate_chain)?,
+                    private_key(&identity.private_key)?,
+                )
+                .map_err(invalid_data)?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(TlsAdaptor::new(
+            TlsConnector::from(Arc::new(config)),
+            domain,
+        ))
+    }
+}
+
+/// Mozilla root certificates bundled by `webpki-roots`, parsed once.
+fn webpki_roots() -> &'static RootCertStore {
+    static ROOTS: OnceLock<RootCertStore> = OnceLock::new();
+    ROOTS.get_or_init(|| {
+        let mut roots = RootCertStore::empty();
+        roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }));
+        roots
+    })
+}
+
+fn certificates(source: &PemSource) -> io::Result<Vec<Certificate>> {
+    let certificates = rustls_pemfile::certs(&mut source.read()?.as_slice())?;
+    if certificates.is_empty() {
+        return Err(invalid_data("no certificate found"));
+    }
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+/// Reads the first PKCS#1, PKCS#8 or SEC1 private key of `source`.
+fn private_key(source: &PemSource) -> io::Result<PrivateKey> {
+    let pem = source.read()?;
+    let mut reader = pem.as_slice();
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(
+                rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::ECKey(key),
+            ) => return Ok(PrivateKey(key)),
+            Some(_) => {}
+            None => return Err(invalid_data("no private key found")),
+        }
+    }
+}
+
+fn invalid_data<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
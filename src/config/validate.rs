@@ -0,0 +1,324 @@
+//! Validation of a [`Config`](super::Config) before a pool is created from it.
+
+use std::{fmt, time::Duration};
+
+use super::{ConnectionConfig, MIN_FRAME_MAX, MIN_HEARTBEAT};
+#[cfg(feature = "oauth2")]
+use crate::credentials::OAuth2Config;
+use crate::PoolError;
+
+/// Error returned when a [`Config`](super::Config) is invalid.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// A [`ConnectionConfig::host`] is empty.
+    EmptyHost,
+    /// A [`ConnectionConfig::port`] is `0`.
+    InvalidPort(u16),
+    /// A [`ConnectionConfig::heartbeat`] is neither `0` nor at least
+    /// [`MIN_HEARTBEAT`].
+    InvalidHeartbeat(u16),
+    /// A [`ConnectionConfig::frame_max`] is below [`MIN_FRAME_MAX`].
+    InvalidFrameMax(u32),
+    /// The maximum size of the pool is `0`.
+    InvalidPoolSize(usize),
+    /// [`ChannelConfig::channels_per_connection`] is `0`.
+    ///
+    /// [`ChannelConfig::channels_per_connection`]: crate::ChannelConfig::channels_per_connection
+    InvalidChannelsPerConnection(usize),
+    /// [`ChannelConfig::channels_per_connection`] exceeds the
+    /// [`ConnectionConfig::channel_max`] of an endpoint.
+    ///
+    /// [`ChannelConfig::channels_per_connection`]: crate::ChannelConfig::channels_per_connection
+    ChannelMaxExceeded {
+        /// Configured number of channels per connection.
+        channels_per_connection: usize,
+        /// Smallest `channel_max` of the endpoints.
+        channel_max: u16,
+    },
+    /// [`Config::min_idle`](super::Config::min_idle) exceeds the maximum size
+    /// of the pool.
+    InvalidMinIdle {
+        /// Configured minimum number of connections.
+        min_idle: usize,
+        /// Maximum size of the pool.
+        max_size: usize,
+    },
+    /// A timeout is zero.
+    ZeroTimeout(&'static str),
+    /// [`RetryPolicy::jitter`](super::RetryPolicy::jitter) or
+    /// [`RecyclePolicy::jitter`](super::RecyclePolicy::jitter) isn't between
+    /// `0.0` and `1.0`.
+    InvalidJitter(f64),
+    /// The TLS configuration of an endpoint can't be used, e.g. because a
+    /// certificate can't be read.
+    #[cfg(feature = "tls")]
+    Tls(std::io::Error),
+    /// [`OAuth2Config::token_url`] isn't an `http` or `https` URL.
+    #[cfg(feature = "oauth2")]
+    InvalidTokenUrl(String),
+    /// The eager connection check of
+    /// [`Config::try_create_pool_and_connect()`](super::Config::try_create_pool_and_connect)
+    /// failed.
+    Connect(Box<PoolError>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EmptyHost => write!(f, "Host must not be empty"),
+            Self::InvalidPort(port) => write!(f, "Invalid port: {port}"),
+            Self::InvalidHeartbeat(heartbeat) => write!(
+                f,
+                "Invalid heartbeat of {heartbeat}s, expected 0 or at least {MIN_HEARTBEAT}s"
+            ),
+            Self::InvalidFrameMax(frame_max) => write!(
+                f,
+                "Invalid frame_max of {frame_max} bytes, expected at least {MIN_FRAME_MAX}"
+            ),
+            Self::InvalidPoolSize(size) => write!(f, "Invalid pool size: {size}"),
+            Self::InvalidChannelsPerConnection(channels) => {
+                write!(f, "Invalid number of channels per connection: {channels}")
+            }
+            Self::ChannelMaxExceeded {
+                channels_per_connection,
+                channel_max,
+            } => write!(
+                f,
+                "{channels_per_connection} channels per connection exceed the `channel_max` of {channel_max}"
+            ),
+            Self::InvalidMinIdle { min_idle, max_size } => write!(
+                f,
+                "`min_idle` of {min_idle} exceeds the maximum pool size of {max_size}"
+            ),
+            Self::ZeroTimeout(name) => write!(f, "`{name}` must not be zero"),
+            Self::InvalidJitter(jitter) => {
+                write!(f, "Invalid jitter of {jitter}, expected 0.0 to 1.0")
+            }
+            #[cfg(feature = "tls")]
+            Self::Tls(e) => write!(f, "Invalid TLS configuration: {e}"),
+            #[cfg(feature = "oauth2")]
+            Self::InvalidTokenUrl(url) => write!(f, "Invalid OAuth 2.0 token URL: {url}"),
+            Self::Connect(e) => write!(f, "Connecting to the broker failed: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "tls")]
+            Self::Tls(e) => Some(e),
+            Self::Connect(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Checks the settings of a connection to one broker.
+pub(super) fn connection(connection: &ConnectionConfig) -> Result<(), ConfigError> {
+    if connection.host.is_empty() {
+        return Err(ConfigError::EmptyHost);
+    }
+    if connection.port == 0 {
+        return Err(ConfigError::InvalidPort(connection.port));
+    }
+    if connection.heartbeat != 0 && connection.heartbeat < MIN_HEARTBEAT {
+        return Err(ConfigError::InvalidHeartbeat(connection.heartbeat));
+    }
+    if let Some(frame_max) = connection.frame_max.filter(|&f| f < MIN_FRAME_MAX) {
+        return Err(ConfigError::InvalidFrameMax(frame_max));
+    }
+    timeout("connection_timeout", connection.connection_timeout)
+}
+
+/// Checks that the timeout called `name` isn't zero if set.
+pub(super) fn timeout(name: &'static str, timeout: Option<Duration>) -> Result<(), ConfigError> {
+    match timeout {
+        Some(timeout) if timeout.is_zero() => Err(ConfigError::ZeroTimeout(name)),
+        _ => Ok(()),
+    }
+}
+
+/// Checks the settings of fetching OAuth 2.0 tokens.
+#[cfg(feature = "oauth2")]
+pub(super) fn oauth2(oauth2: &OAuth2Config) -> Result<(), ConfigError> {
+    let url = oauth2.token_url.to_ascii_lowercase();
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(ConfigError::InvalidTokenUrl(oauth2.token_url.clone()));
+    }
+    timeout("oauth2.request_timeout", Some(oauth2.request_timeout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::CircuitBreakerConfig, ChannelConfig, Config, PoolConfig};
+
+    fn config(connection: ConnectionConfig) -> Config {
+        Config {
+            connection: Some(connection),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn accepts_defaults() {
+        assert!(config(ConnectionConfig::default()).validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_connections() {
+        let invalid = |connection| config(connection).validate().unwrap_err();
+        assert!(matches!(
+            invalid(ConnectionConfig {
+                host: String::new(),
+                ..ConnectionConfig::default()
+            }),
+            ConfigError::EmptyHost
+        ));
+        assert!(matches!(
+            invalid(ConnectionConfig {
+                port: 0,
+                ..ConnectionConfig::default()
+            }),
+            ConfigError::InvalidPort(0)
+        ));
+        assert!(matches!(
+            invalid(ConnectionConfig {
+                heartbeat: MIN_HEARTBEAT - 1,
+                ..ConnectionConfig::default()
+            }),
+            ConfigError::InvalidHeartbeat(_)
+        ));
+        assert!(matches!(
+            invalid(ConnectionConfig {
+                frame_max: Some(MIN_FRAME_MAX - 1),
+                ..ConnectionConfig::default()
+            }),
+            ConfigError::InvalidFrameMax(_)
+        ));
+        assert!(matches!(
+            invalid(ConnectionConfig {
+                connection_timeout: Some(Duration::ZERO),
+                ..ConnectionConfig::default()
+            }),
+            ConfigError::ZeroTimeout("connection_timeout")
+        ));
+    }
+
+    #[test]
+    fn accepts_disabled_heartbeat() {
+        let connection = ConnectionConfig {
+            heartbeat: 0,
+            ..ConnectionConfig::default()
+        };
+        assert!(config(connection).validate().is_ok());
+    }
+
+    #[test]
+    fn checks_every_endpoint() {
+        let mut config = config(ConnectionConfig::default());
+        config.endpoints = vec![
+            ConnectionConfig::default(),
+            ConnectionConfig {
+                port: 0,
+                ..ConnectionConfig::default()
+            },
+        ];
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidPort(0))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_pool_sizes() {
+        let mut config = config(ConnectionConfig::default());
+        config.pool_config = Some(PoolConfig::new(0));
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidPoolSize(0))
+        ));
+
+        config.pool_config = Some(PoolConfig::new(2));
+        config.min_idle = 3;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidMinIdle {
+                min_idle: 3,
+                max_size: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_jitter() {
+        let mut config = config(ConnectionConfig::default());
+        config.retry.jitter = 1.5;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidJitter(_))
+        ));
+
+        config.retry.jitter = 0.0;
+        config.recycle_policy.jitter = f64::NAN;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidJitter(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_zero_timeouts() {
+        let zero_timeout = |update: fn(&mut Config)| {
+            let mut config = config(ConnectionConfig::default());
+            update(&mut config);
+            match config.validate() {
+                Err(ConfigError::ZeroTimeout(name)) => name,
+                result => panic!("expected a zero timeout, got {result:?}"),
+            }
+        };
+        assert_eq!(
+            zero_timeout(|c| c.retry.attempt_timeout = Some(Duration::ZERO)),
+            "retry.attempt_timeout"
+        );
+        assert_eq!(
+            zero_timeout(|c| {
+                c.circuit_breaker = Some(CircuitBreakerConfig {
+                    reset_timeout: Duration::ZERO,
+                    ..CircuitBreakerConfig::default()
+                });
+            }),
+            "circuit_breaker.reset_timeout"
+        );
+        assert_eq!(
+            zero_timeout(|c| c.recycle_timeout = Some(Duration::ZERO)),
+            "recycle_timeout"
+        );
+        assert_eq!(
+            zero_timeout(|c| c.close_timeout = Duration::ZERO),
+            "close_timeout"
+        );
+    }
+
+    #[test]
+    fn rejects_channels_exceeding_channel_max() {
+        let pool = config(ConnectionConfig {
+            channel_max: Some(4),
+            ..ConnectionConfig::default()
+        })
+        .create_pool();
+        assert!(ChannelConfig::new(4, None).validate(&pool).is_ok());
+        assert!(matches!(
+            ChannelConfig::new(5, None).validate(&pool),
+            Err(ConfigError::ChannelMaxExceeded {
+                channels_per_connection: 5,
+                channel_max: 4
+            })
+        ));
+        assert!(matches!(
+            ChannelConfig::new(0, None).validate(&pool),
+            Err(ConfigError::InvalidChannelsPerConnection(0))
+        ));
+    }
+}
@@ -0,0 +1,336 @@
+//! Connections managed by the [`Pool`] and the events the broker reported for
+//! them.
+//!
+//! Every connection created by the [`Manager`](crate::Manager) gets a
+//! [`ConnectionCallback`] which records a server-initiated `connection.close`
+//! and `connection.blocked`/`connection.unblocked` in its [`ConnectionState`].
+//! Connections closed by the broker are discarded when recycled, and
+//! [`spawn_eviction_task()`] removes them from the idle queue as soon as the
+//! close arrives.
+//!
+//! Connections discarded by the [`Pool`], e.g. because they failed recycling
+//! or the pool was closed, are closed with a `connection.close` handshake
+//! instead of just dropping the socket, see [`Config::close_timeout`].
+//!
+//! How connections are opened can be customised with a [`ConnectionFactory`].
+//!
+//! amqprs supports only one callback per connection, so registering another
+//! one with [`Connection::register_callback()`] stops the [`ConnectionState`]
+//! from being updated.
+
+use std::{
+    fmt,
+    ops::Deref,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use amqprs::{
+    callbacks::ConnectionCallback,
+    connection::{Connection, OpenConnectionArguments},
+    Close,
+};
+use deadpool::async_trait;
+use tokio::{
+    sync::{watch, Notify},
+    task::JoinHandle,
+};
+
+#[cfg(doc)]
+use crate::Config;
+use crate::Pool;
+
+/// Opens the connections of a [`Manager`](crate::Manager).
+///
+/// Implement this to customise how connections are established, e.g. to
+/// adjust the [`OpenConnectionArguments`] per connection, record connection
+/// attempts or connect to a stand-in broker in tests. The
+/// [`Manager`](crate::Manager) registers its own [`ConnectionCallback`] on
+/// the returned connection and declares the topology on it.
+///
+/// # Example
+///
+/// ```rs
+/// use deadpool_amqprs::{
+///     amqprs::connection::{Connection, OpenConnectionArguments},
+///     connection::ConnectionFactory,
+/// };
+///
+/// struct NamedConnections;
+///
+/// #[async_trait::async_trait]
+/// impl ConnectionFactory for NamedConnections {
+///     async fn connect(
+///         &self,
+///         args: &OpenConnectionArguments,
+///     ) -> Result<Connection, amqprs::error::Error> {
+///         let mut args = args.clone();
+///         args.connection_name(&format!("orders-{}", std::process::id()));
+///         Connection::open(&args).await
+///     }
+/// }
+///
+/// config.connection_factory = Some(Arc::new(NamedConnections));
+/// ```
+#[async_trait]
+pub trait ConnectionFactory: Send + Sync {
+    /// Opens a connection using `args`, which were built from the
+    /// configuration of the endpoint tried.
+    async fn connect(
+        &self,
+        args: &OpenConnectionArguments,
+    ) -> Result<Connection, amqprs::error::Error>;
+}
+
+/// [`ConnectionFactory`] calling [`Connection::open()`], used unless another
+/// one is configured.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultConnectionFactory;
+
+#[async_trait]
+impl ConnectionFactory for DefaultConnectionFactory {
+    async fn connect(
+        &self,
+        args: &OpenConnectionArguments,
+    ) -> Result<Connection, amqprs::error::Error> {
+        Connection::open(args).await
+    }
+}
+
+/// [`Connection`] handed out by the [`Pool`].
+///
+/// Dereferences to [`Connection`], so it can be used just like one.
+pub struct ManagedConnection {
+    inner: Connection,
+    state: Arc<ConnectionState>,
+    /// Random share of the [`RecyclePolicy`](crate::RecyclePolicy) jitter
+    /// applied to this connection.
+    jitter: f64,
+    /// Closes this connection once it is dropped, unset for connections
+    /// which weren't created by a [`Manager`](crate::Manager) yet or were
+    /// taken out with [`ManagedConnection::into_inner()`].
+    closer: Option<Closer>,
+}
+
+impl ManagedConnection {
+    /// Opens a new connection with `factory` and registers a callback
+    /// recording the events reported by the broker.
+    pub(crate) async fn open(
+        factory: &dyn ConnectionFactory,
+        args: &OpenConnectionArguments,
+        evictions: &Arc<Notify>,
+    ) -> Result<Self, amqprs::error::Error> {
+        let inner = factory.connect(args).await?;
+        let state = Arc::new(ConnectionState::default());
+        inner
+            .register_callback(StateCallback {
+                state: Arc::clone(&state),
+                evictions: Arc::clone(evictions),
+            })
+            .await?;
+        Ok(Self {
+            inner,
+            state,
+            jitter: fastrand::f64(),
+            closer: None,
+        })
+    }
+
+    /// Returns the events the broker reported for this connection.
+    #[must_use]
+    pub fn state(&self) -> &ConnectionState {
+        &self.state
+    }
+
+    /// Returns the random share of the recycle policy jitter applied to this
+    /// connection, between `0.0` and `1.0`.
+    pub(crate) const fn jitter(&self) -> f64 {
+        self.jitter
+    }
+
+    /// Closes this connection with `closer` once it is dropped.
+    pub(crate) fn close_on_drop(&mut self, closer: Closer) {
+        self.closer = Some(closer);
+    }
+
+    /// Returns whether this connection is open and wasn't closed by the
+    /// broker.
+    #[must_use]
+    pub fn is_usable(&self) -> bool {
+        self.inner.is_open() && self.state.server_close().is_none()
+    }
+
+    /// Returns the underlying [`Connection`].
+    ///
+    /// The connection is no longer closed once this [`ManagedConnection`] is
+    /// dropped, the caller is responsible for closing it.
+    #[must_use]
+    pub fn into_inner(mut self) -> Connection {
+        self.closer = None;
+        self.inner.clone()
+    }
+}
+
+impl Drop for ManagedConnection {
+    fn drop(&mut self) {
+        if let Some(closer) = &self.closer {
+            closer.close(&self.inner);
+        }
+    }
+}
+
+impl Deref for ManagedConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.inner
+    }
+}
+
+impl fmt::Debug for ManagedConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ManagedConnection")
+            .field("state", &self.state)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Closes dropped connections in the background and keeps track of the
+/// closes still in progress.
+#[derive(Clone)]
+pub(crate) struct Closer {
+    timeout: Duration,
+    pending: Arc<watch::Sender<usize>>,
+}
+
+impl Closer {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            pending: Arc::new(watch::Sender::new(0)),
+        }
+    }
+
+    pub(crate) const fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Starts closing `conn` unless it is closed already.
+    ///
+    /// Outside of a Tokio runtime the connection is just dropped.
+    fn close(&self, conn: &Connection) {
+        if !conn.is_open() {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let conn = conn.clone();
+        let timeout = self.timeout;
+        let pending = Arc::clone(&self.pending);
+        pending.send_modify(|pending| *pending += 1);
+        runtime.spawn(async move {
+            let _ = tokio::time::timeout(timeout, conn.close()).await;
+            pending.send_modify(|pending| *pending -= 1);
+        });
+    }
+
+    /// Waits until all closes started so far finished or timed out.
+    pub(crate) async fn wait(&self) {
+        let _ = self
+            .pending
+            .subscribe()
+            .wait_for(|pending| *pending == 0)
+            .await;
+    }
+}
+
+/// `connection.close` sent by the broker.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServerClose {
+    /// Reply code, e.g. `320` (`CONNECTION_FORCED`) during a node restart.
+    pub reply_code: u16,
+    /// Reply text explaining the close.
+    pub reply_text: String,
+}
+
+/// Events the broker reported for a [`ManagedConnection`].
+#[derive(Debug, Default)]
+pub struct ConnectionState {
+    server_close: Mutex<Option<ServerClose>>,
+    blocked: Mutex<Option<String>>,
+}
+
+impl ConnectionState {
+    /// Returns the `connection.close` sent by the broker, if any.
+    #[must_use]
+    pub fn server_close(&self) -> Option<ServerClose> {
+        self.server_close.lock().unwrap().clone()
+    }
+
+    /// Returns the reason the broker gave for blocking publishes on this
+    /// connection, if it is currently blocked.
+    ///
+    /// Blocked connections are not discarded, as the broker blocks all
+    /// publishing connections alike, e.g. when a memory alarm is raised.
+    #[must_use]
+    pub fn blocked_reason(&self) -> Option<String> {
+        self.blocked.lock().unwrap().clone()
+    }
+
+    /// Returns whether the broker currently blocks publishes on this
+    /// connection.
+    #[must_use]
+    pub fn is_blocked(&self) -> bool {
+        self.blocked.lock().unwrap().is_some()
+    }
+}
+
+struct StateCallback {
+    state: Arc<ConnectionState>,
+    evictions: Arc<Notify>,
+}
+
+#[async_trait]
+impl ConnectionCallback for StateCallback {
+    async fn close(&mut self, _: &Connection, close: Close) -> Result<(), amqprs::error::Error> {
+        *self.state.server_close.lock().unwrap() = Some(ServerClose {
+            reply_code: close.reply_code(),
+            reply_text: close.reply_text().to_string(),
+        });
+        self.evictions.notify_one();
+        Ok(())
+    }
+
+    async fn blocked(&mut self, _: &Connection, reason: String) {
+        *self.state.blocked.lock().unwrap() = Some(reason);
+    }
+
+    async fn unblocked(&mut self, _: &Connection) {
+        *self.state.blocked.lock().unwrap() = None;
+    }
+}
+
+/// Spawns a task removing idle connections which are closed or were closed
+/// by the broker from `pool`.
+///
+/// The task sweeps the idle connections whenever the broker closes a
+/// connection of the pool and at least every `interval`.
+///
+/// The task holds a clone of `pool` and only stops once the pool is closed,
+/// so the pool isn't freed before [`Pool::close()`](deadpool::managed::Pool::close)
+/// is called, even if the returned [`JoinHandle`] is dropped.
+///
+/// # Panics
+///
+/// Panics if called outside of a tokio runtime.
+pub fn spawn_eviction_task(pool: &Pool, interval: Duration) -> JoinHandle<()> {
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        let evictions = Arc::clone(&pool.manager().evictions);
+        while !pool.is_closed() {
+            let _ = tokio::time::timeout(interval, evictions.notified()).await;
+            pool.retain(|conn, _| conn.is_usable());
+        }
+    })
+}
@@ -0,0 +1,392 @@
+//! Consumers which survive connection failures.
+//!
+//! A consumer started with `basic_consume` stops for good once the channel or
+//! connection it was started on dies. A [`ConsumerStream`] instead checks a
+//! new connection out of the [`Pool`], opens a new channel, applies the
+//! prefetch count and consumes again, until the stream is dropped.
+//!
+//! Deliveries received before a failure can't be acknowledged anymore, the
+//! broker redelivers them on the new channel. The stream yields
+//! [`ConsumerEvent::Disconnected`] as soon as a failure is noticed and
+//! [`ConsumerEvent::Reconnected`] once consuming resumed, so handlers can drop
+//! state belonging to unacknowledged deliveries.
+//!
+//! # Example
+//!
+//! ```rs
+//! use deadpool_amqprs::{consumer::ConsumerEvent, ConsumerConfig};
+//! use futures::StreamExt;
+//!
+//! let mut config = ConsumerConfig::new("orders.created");
+//! config.prefetch_count = 16;
+//! let mut deliveries = config.consume(pool.clone());
+//!
+//! while let Some(event) = deliveries.next().await {
+//!     match event {
+//!         ConsumerEvent::Delivery(delivery) => {
+//!             // Handle `delivery.content`.
+//!             delivery.ack().await.unwrap();
+//!         }
+//!         ConsumerEvent::Disconnected(e) => eprintln!("consumer disconnected: {e}"),
+//!         ConsumerEvent::Reconnected => {}
+//!     }
+//! }
+//! ```
+
+use std::{
+    fmt,
+    pin::Pin,
+    sync::{Arc, Weak},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use amqprs::{
+    channel::{
+        BasicAckArguments, BasicCancelArguments, BasicConsumeArguments, BasicNackArguments,
+        BasicQosArguments, BasicRejectArguments, Channel,
+    },
+    consumer::AsyncConsumer,
+    BasicProperties, Deliver,
+};
+use deadpool::async_trait;
+use futures_core::Stream;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{channel::PooledChannel, Pool, PoolError, PoolExt};
+
+/// Default value of [`ConsumerConfig::prefetch_count`].
+pub const DEFAULT_PREFETCH_COUNT: u16 = 64;
+
+/// Default value of [`ConsumerConfig::reconnect_delay`].
+pub const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Interval at which the channel of a consumer is checked for being closed.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Configuration of a [`ConsumerStream`].
+#[derive(Clone, Debug)]
+pub struct ConsumerConfig {
+    /// Name of the queue to consume from.
+    pub queue: String,
+    /// Consumer tag, or an empty string to let the broker generate one.
+    ///
+    /// Default: empty
+    pub consumer_tag: String,
+    /// Maximum number of unacknowledged deliveries, `0` for no limit.
+    ///
+    /// Deliveries are buffered by the [`ConsumerStream`] until they are
+    /// polled, so this also bounds its memory use. The broker ignores it with
+    /// [`ConsumerConfig::no_ack`], so such streams grow without bound if they
+    /// aren't polled fast enough.
+    ///
+    /// Default: [`DEFAULT_PREFETCH_COUNT`]
+    pub prefetch_count: u16,
+    /// Whether deliveries are acknowledged by the broker once they are sent.
+    ///
+    /// Default: `false`
+    pub no_ack: bool,
+    /// Whether no other consumer may consume from the queue.
+    ///
+    /// Default: `false`
+    pub exclusive: bool,
+    /// Time to wait before consuming again after a failure.
+    ///
+    /// Default: [`DEFAULT_RECONNECT_DELAY`]
+    pub reconnect_delay: Duration,
+}
+
+impl ConsumerConfig {
+    /// Creates a new config consuming from `queue`.
+    #[must_use]
+    pub fn new(queue: &str) -> Self {
+        Self {
+            queue: queue.to_owned(),
+            consumer_tag: String::new(),
+            prefetch_count: DEFAULT_PREFETCH_COUNT,
+            no_ack: false,
+            exclusive: false,
+            reconnect_delay: DEFAULT_RECONNECT_DELAY,
+        }
+    }
+
+    /// Starts consuming on connections from `pool`.
+    ///
+    /// Consuming stops once the returned stream is dropped or `pool` is
+    /// closed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a Tokio runtime.
+    #[must_use]
+    pub fn consume(&self, pool: Pool) -> ConsumerStream {
+        let (events, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run(pool, self.clone(), events));
+        ConsumerStream { events: receiver }
+    }
+}
+
+/// Error because of which a [`ConsumerStream`] is reconnecting.
+#[derive(Debug)]
+pub enum ConsumerError {
+    /// No connection could be checked out of the [`Pool`].
+    Pool(PoolError),
+    /// Opening the channel or starting the consumer failed.
+    Amqp(amqprs::error::Error),
+    /// The channel, connection or consumer was closed.
+    Closed,
+}
+
+impl fmt::Display for ConsumerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pool(e) => write!(f, "Connection pool error: {e}"),
+            Self::Amqp(e) => write!(f, "Starting consumer failed: {e}"),
+            Self::Closed => write!(f, "Consumer closed"),
+        }
+    }
+}
+
+impl std::error::Error for ConsumerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Pool(e) => Some(e),
+            Self::Amqp(e) => Some(e),
+            Self::Closed => None,
+        }
+    }
+}
+
+impl From<PoolError> for ConsumerError {
+    fn from(e: PoolError) -> Self {
+        Self::Pool(e)
+    }
+}
+
+impl From<amqprs::error::Error> for ConsumerError {
+    fn from(e: amqprs::error::Error) -> Self {
+        Self::Amqp(e)
+    }
+}
+
+/// Event yielded by a [`ConsumerStream`].
+#[derive(Debug)]
+pub enum ConsumerEvent {
+    /// A message was delivered.
+    Delivery(Box<Delivery>),
+    /// The consumer stopped, unacknowledged deliveries will be redelivered.
+    ///
+    /// Yielded again for every failed attempt to consume again.
+    Disconnected(ConsumerError),
+    /// The consumer was started again after being disconnected.
+    Reconnected,
+}
+
+/// Message delivered to a [`ConsumerStream`].
+///
+/// Holds on to the channel it was delivered on, so it can still be
+/// acknowledged after the stream moved on to a new channel, as long as the
+/// old one is open.
+pub struct Delivery {
+    /// Delivery metadata, e.g. the delivery tag and routing key.
+    pub deliver: Deliver,
+    /// Properties of the message.
+    pub properties: BasicProperties,
+    /// Body of the message.
+    pub content: Vec<u8>,
+    channel: Arc<PooledChannel>,
+}
+
+impl Delivery {
+    /// Acknowledges this delivery.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the channel it was delivered on is closed.
+    pub async fn ack(&self) -> Result<(), amqprs::error::Error> {
+        self.channel
+            .basic_ack(BasicAckArguments::new(self.deliver.delivery_tag(), false))
+            .await
+    }
+
+    /// Rejects this delivery, putting it back into the queue if `requeue` is
+    /// set.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the channel it was delivered on is closed.
+    pub async fn nack(&self, requeue: bool) -> Result<(), amqprs::error::Error> {
+        self.channel
+            .basic_nack(BasicNackArguments::new(
+                self.deliver.delivery_tag(),
+                false,
+                requeue,
+            ))
+            .await
+    }
+
+    /// Rejects this delivery with `basic.reject`, putting it back into the
+    /// queue if `requeue` is set.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the channel it was delivered on is closed.
+    pub async fn reject(&self, requeue: bool) -> Result<(), amqprs::error::Error> {
+        self.channel
+            .basic_reject(BasicRejectArguments::new(
+                self.deliver.delivery_tag(),
+                requeue,
+            ))
+            .await
+    }
+}
+
+impl fmt::Debug for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Delivery")
+            .field("deliver", &self.deliver)
+            .field("properties", &self.properties)
+            .field("content", &self.content.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// [`Stream`] of [`ConsumerEvent`]s returned by [`ConsumerConfig::consume()`].
+///
+/// Ends once the connection [`Pool`] is closed.
+#[derive(Debug)]
+pub struct ConsumerStream {
+    events: mpsc::UnboundedReceiver<ConsumerEvent>,
+}
+
+impl Stream for ConsumerStream {
+    type Item = ConsumerEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+/// Forwards deliveries to the [`ConsumerStream`].
+struct Forwarder {
+    events: mpsc::UnboundedSender<ConsumerEvent>,
+    channel: Weak<PooledChannel>,
+    /// Dropped together with the consumer, which tells [`Subscription`] it
+    /// stopped.
+    _dropped: oneshot::Sender<()>,
+}
+
+#[async_trait]
+impl AsyncConsumer for Forwarder {
+    async fn consume(
+        &mut self,
+        _: &Channel,
+        deliver: Deliver,
+        properties: BasicProperties,
+        content: Vec<u8>,
+    ) {
+        let Some(channel) = self.channel.upgrade() else {
+            return;
+        };
+        let _ = self.events.send(ConsumerEvent::Delivery(Box::new(Delivery {
+            deliver,
+            properties,
+            content,
+            channel,
+        })));
+    }
+}
+
+/// Consumer started on a channel.
+struct Subscription {
+    channel: Arc<PooledChannel>,
+    consumer_tag: String,
+    dropped: oneshot::Receiver<()>,
+}
+
+impl Subscription {
+    async fn start(
+        pool: &Pool,
+        config: &ConsumerConfig,
+        events: &mpsc::UnboundedSender<ConsumerEvent>,
+    ) -> Result<Self, ConsumerError> {
+        let connection = Arc::new(pool.checkout().await?);
+        let channel = Arc::new(PooledChannel::open(connection).await?);
+        if config.prefetch_count > 0 {
+            channel
+                .basic_qos(BasicQosArguments::new(0, config.prefetch_count, false))
+                .await?;
+        }
+        let (dropped_sender, dropped) = oneshot::channel();
+        let forwarder = Forwarder {
+            events: events.clone(),
+            channel: Arc::downgrade(&channel),
+            _dropped: dropped_sender,
+        };
+        let args = BasicConsumeArguments::new(&config.queue, &config.consumer_tag)
+            .manual_ack(!config.no_ack)
+            .exclusive(config.exclusive)
+            .finish();
+        let consumer_tag = channel.basic_consume(forwarder, args).await?;
+        Ok(Self {
+            channel,
+            consumer_tag,
+            dropped,
+        })
+    }
+
+    /// Waits until the consumer stopped, or returns [`None`] once the stream
+    /// was dropped.
+    async fn wait(
+        &mut self,
+        events: &mpsc::UnboundedSender<ConsumerEvent>,
+    ) -> Option<ConsumerError> {
+        loop {
+            if tokio::time::timeout(CHECK_INTERVAL, &mut self.dropped)
+                .await
+                .is_ok()
+            {
+                return Some(ConsumerError::Closed);
+            }
+            if events.is_closed() {
+                return None;
+            }
+            if !self.channel.is_open() || !self.channel.connection().is_open() {
+                return Some(ConsumerError::Closed);
+            }
+        }
+    }
+
+    /// Cancels the consumer, the channel is closed once the last
+    /// [`Delivery`] received on it is dropped.
+    async fn cancel(self) {
+        let args = BasicCancelArguments::new(&self.consumer_tag);
+        let _ = self.channel.basic_cancel(args).await;
+    }
+}
+
+async fn run(pool: Pool, config: ConsumerConfig, events: mpsc::UnboundedSender<ConsumerEvent>) {
+    let mut reconnecting = false;
+    loop {
+        let error = match Subscription::start(&pool, &config, &events).await {
+            Ok(mut subscription) => {
+                let stopped = if reconnecting && events.send(ConsumerEvent::Reconnected).is_err() {
+                    None
+                } else {
+                    subscription.wait(&events).await
+                };
+                match stopped {
+                    Some(error) => error,
+                    None => return subscription.cancel().await,
+                }
+            }
+            Err(error) => error,
+        };
+        let pool_closed = matches!(error, ConsumerError::Pool(PoolError::Closed));
+        if events.send(ConsumerEvent::Disconnected(error)).is_err() || pool_closed {
+            return;
+        }
+        reconnecting = true;
+        tokio::time::sleep(config.reconnect_delay).await;
+    }
+}
@@ -0,0 +1,310 @@
+//! Credentials used to authenticate new connections.
+//!
+//! By default connections authenticate with the username and password of the
+//! [`ConnectionConfig`](crate::ConnectionConfig) or
+//! [`OpenConnectionArguments`](amqprs::connection::OpenConnectionArguments)
+//! the pool was created with. A [`CredentialsProvider`] is instead asked for
+//! the credentials whenever a connection is opened, so rotated secrets are
+//! picked up by new connections while existing ones keep running until they
+//! are recycled or closed.
+//!
+//! # Example
+//!
+//! ```rs
+//! use deadpool_amqprs::credentials::FileCredentials;
+//!
+//! // Kubernetes secret mounted as a volume.
+//! config.credentials_provider = Some(Arc::new(FileCredentials::new(
+//!     "/var/run/secrets/rabbitmq/username",
+//!     "/var/run/secrets/rabbitmq/password",
+//! )));
+//! ```
+//!
+//! With the `oauth2` feature enabled [`OAuth2Credentials`] authenticate with
+//! access tokens of the OAuth 2.0 client credentials grant, as required by
+//! the RabbitMQ OAuth 2.0 auth backend.
+
+#[cfg(feature = "oauth2")]
+mod oauth2;
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use deadpool::async_trait;
+
+#[cfg(feature = "oauth2")]
+pub use self::oauth2::{
+    OAuth2Config, OAuth2Credentials, DEFAULT_REFRESH_MARGIN, DEFAULT_REQUEST_TIMEOUT,
+};
+
+/// Error returned by a [`CredentialsProvider`].
+pub type CredentialsError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Username and password used for `PLAIN` authentication.
+#[derive(Clone, Eq, PartialEq)]
+pub struct Credentials {
+    /// User name.
+    pub username: String,
+    /// Password.
+    pub password: String,
+}
+
+impl Credentials {
+    /// Creates new credentials.
+    #[must_use]
+    pub fn new(username: &str, password: &str) -> Self {
+        Self {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        }
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Provides the [`Credentials`] of new connections of a
+/// [`Manager`](crate::Manager).
+///
+/// Called once per attempt to open a connection, see
+/// [`RetryPolicy`](crate::RetryPolicy), before any endpoint is tried. The
+/// credentials apply to every endpoint.
+#[async_trait]
+pub trait CredentialsProvider: Send + Sync {
+    /// Returns the credentials to open the next connection with.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no credentials are available, which fails the
+    /// attempt with [`Error::Credentials`](crate::Error::Credentials).
+    async fn credentials(&self) -> Result<Credentials, CredentialsError>;
+}
+
+/// [`CredentialsProvider`] always returning the same [`Credentials`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StaticCredentials(pub Credentials);
+
+#[async_trait]
+impl CredentialsProvider for StaticCredentials {
+    async fn credentials(&self) -> Result<Credentials, CredentialsError> {
+        Ok(self.0.clone())
+    }
+}
+
+/// [`CredentialsProvider`] reading the username and password from
+/// environment variables whenever a connection is opened.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EnvCredentials {
+    username_var: String,
+    password_var: String,
+}
+
+impl EnvCredentials {
+    /// Reads the username from `username_var` and the password from
+    /// `password_var`.
+    #[must_use]
+    pub fn new(username_var: &str, password_var: &str) -> Self {
+        Self {
+            username_var: username_var.to_owned(),
+            password_var: password_var.to_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl CredentialsProvider for EnvCredentials {
+    async fn credentials(&self) -> Result<Credentials, CredentialsError> {
+        let var = |name: &str| {
+            std::env::var(name).map_err(|e| format!("Environment variable `{name}`: {e}"))
+        };
+        Ok(Credentials {
+            username: var(&self.username_var)?,
+            password: var(&self.password_var)?,
+        })
+    }
+}
+
+/// [`CredentialsProvider`] reading the username and password from files,
+/// e.g. mounted secrets.
+///
+/// The files are read again once their modification time changes, so
+/// rotated secrets are used by the next connection. Trailing line breaks are
+/// removed.
+pub struct FileCredentials {
+    username: Source,
+    password: SecretFile,
+}
+
+/// Where the username of [`FileCredentials`] comes from.
+enum Source {
+    Fixed(String),
+    File(SecretFile),
+}
+
+/// File holding a secret, cached until it's modified.
+struct SecretFile {
+    path: PathBuf,
+    /// Modification time and content of the file when it was last read.
+    cached: Mutex<Option<(SystemTime, String)>>,
+}
+
+impl FileCredentials {
+    /// Reads the username from `username_file` and the password from
+    /// `password_file`.
+    #[must_use]
+    pub fn new(username_file: impl AsRef<Path>, password_file: impl AsRef<Path>) -> Self {
+        Self {
+            username: Source::File(SecretFile::new(username_file.as_ref())),
+            password: SecretFile::new(password_file.as_ref()),
+        }
+    }
+
+    /// Uses `username` and reads the password from `password_file`.
+    #[must_use]
+    pub fn with_username(username: &str, password_file: impl AsRef<Path>) -> Self {
+        Self {
+            username: Source::Fixed(username.to_owned()),
+            password: SecretFile::new(password_file.as_ref()),
+        }
+    }
+}
+
+impl SecretFile {
+    fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Returns the content of the file, reading it again if it was modified
+    /// since it was last read.
+    async fn read(&self) -> Result<String, CredentialsError> {
+        let error = |e: std::io::Error| format!("Reading `{}`: {e}", self.path.display());
+        let modified = tokio::fs::metadata(&self.path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .map_err(error)?;
+        if let Some((at, content)) = &*self.cached.lock().unwrap() {
+            if *at == modified {
+                return Ok(content.clone());
+            }
+        }
+        let content = tokio::fs::read_to_string(&self.path).await.map_err(error)?;
+        let content = content.trim_end_matches(['\r', '\n']).to_owned();
+        *self.cached.lock().unwrap() = Some((modified, content.clone()));
+        Ok(content)
+    }
+}
+
+#[async_trait]
+impl CredentialsProvider for FileCredentials {
+    async fn credentials(&self) -> Result<Credentials, CredentialsError> {
+        let username = match &self.username {
+            Source::Fixed(username) => username.clone(),
+            Source::File(file) => file.read().await?,
+        };
+        Ok(Credentials {
+            username,
+            password: self.password.read().await?,
+        })
+    }
+}
+
+impl fmt::Debug for FileCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut f = f.debug_struct("FileCredentials");
+        match &self.username {
+            Source::Fixed(username) => f.field("username", username),
+            Source::File(file) => f.field("username_file", &file.path),
+        };
+        f.field("password_file", &self.password.path).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Directory removed again when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("deadpool-amqprs-{name}-{}", std::process::id()));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        /// Writes `content` to `name` and sets its modification time, so
+        /// tests don't depend on the timestamp resolution of the file system.
+        fn write(&self, name: &str, content: &str, modified: SystemTime) -> PathBuf {
+            let path = self.0.join(name);
+            std::fs::write(&path, content).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn file_credentials_trim_line_breaks() {
+        let dir = TempDir::new("trim");
+        let now = SystemTime::now();
+        let provider = FileCredentials::new(
+            dir.write("username", "orders\n", now),
+            dir.write("password", "secret\r\n", now),
+        );
+        assert_eq!(
+            provider.credentials().await.unwrap(),
+            Credentials::new("orders", "secret")
+        );
+    }
+
+    #[tokio::test]
+    async fn file_credentials_reload_modified_files() {
+        let dir = TempDir::new("reload");
+        let now = SystemTime::now();
+        let password = dir.write("password", "old", now);
+        let provider = FileCredentials::with_username("orders", &password);
+        assert_eq!(provider.credentials().await.unwrap().password, "old");
+
+        // Unmodified files are served from the cache.
+        dir.write("password", "unnoticed", now);
+        assert_eq!(provider.credentials().await.unwrap().password, "old");
+
+        dir.write("password", "new", now + Duration::from_secs(1));
+        assert_eq!(provider.credentials().await.unwrap().password, "new");
+    }
+
+    #[tokio::test]
+    async fn file_credentials_fail_for_missing_files() {
+        let dir = TempDir::new("missing");
+        let provider = FileCredentials::with_username("orders", dir.0.join("password"));
+        let error = provider.credentials().await.unwrap_err();
+        assert!(error.to_string().contains("password"), "{error}");
+    }
+}
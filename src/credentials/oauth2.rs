@@ -0,0 +1,272 @@
+//! OAuth 2.0 client credentials grant for the RabbitMQ OAuth 2.0 auth
+//! backend, which expects a JWT as the password.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use deadpool::async_trait;
+use tokio::sync::Mutex;
+
+use super::{Credentials, CredentialsError, CredentialsProvider};
+
+/// Default value of [`OAuth2Config::refresh_margin`].
+pub const DEFAULT_REFRESH_MARGIN: Duration = Duration::from_secs(30);
+
+/// Default value of [`OAuth2Config::request_timeout`].
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Configuration of [`OAuth2Credentials`].
+///
+/// # Example
+///
+/// ```toml
+/// [rabbitmq.oauth2]
+/// token_url = "https://uaa.internal/oauth/token"
+/// client_id = "orders"
+/// client_secret = "..."
+/// scope = "rabbitmq.read:*/* rabbitmq.write:*/* rabbitmq.configure:*/*"
+/// ```
+#[derive(Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct OAuth2Config {
+    /// URL of the token endpoint of the authorization server.
+    pub token_url: String,
+    /// Client ID sent to the token endpoint.
+    pub client_id: String,
+    /// Client secret sent to the token endpoint.
+    pub client_secret: String,
+    /// Space separated scopes requested for the token.
+    ///
+    /// Default: none, the authorization server's defaults apply
+    pub scope: Option<String>,
+    /// User name sent to the broker together with the token.
+    ///
+    /// The OAuth 2.0 backend takes the identity from the token, so this only
+    /// shows up in logs and the management UI.
+    ///
+    /// Default: [`OAuth2Config::client_id`]
+    pub username: Option<String>,
+    /// How long before it expires a cached token is replaced by a new one,
+    /// so connections aren't opened with a token expiring during the
+    /// handshake.
+    ///
+    /// Default: [`DEFAULT_REFRESH_MARGIN`]
+    pub refresh_margin: Duration,
+    /// Maximum duration of a request to the token endpoint.
+    ///
+    /// Default: [`DEFAULT_REQUEST_TIMEOUT`]
+    pub request_timeout: Duration,
+}
+
+impl OAuth2Config {
+    /// Creates a new config fetching tokens for `client_id` from
+    /// `token_url`.
+    #[must_use]
+    pub fn new(token_url: &str, client_id: &str, client_secret: &str) -> Self {
+        Self {
+            token_url: token_url.to_owned(),
+            client_id: client_id.to_owned(),
+            client_secret: client_secret.to_owned(),
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for OAuth2Config {
+    fn default() -> Self {
+        Self {
+            token_url: String::new(),
+            client_id: String::new(),
+            client_secret: String::new(),
+            scope: None,
+            username: None,
+            refresh_margin: DEFAULT_REFRESH_MARGIN,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+}
+
+impl fmt::Debug for OAuth2Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OAuth2Config")
+            .field("token_url", &self.token_url)
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"<redacted>")
+            .field("scope", &self.scope)
+            .field("username", &self.username)
+            .field("refresh_margin", &self.refresh_margin)
+            .field("request_timeout", &self.request_timeout)
+            .finish()
+    }
+}
+
+/// [`CredentialsProvider`] using an access token obtained with the OAuth 2.0
+/// client credentials grant as the password.
+///
+/// The token is cached until [`OAuth2Config::refresh_margin`] before it
+/// expires and shared by all connections opened meanwhile. Tokens without an
+/// `expires_in` aren't cached.
+///
+/// RabbitMQ closes connections once their token expires, and this crate
+/// doesn't pass new tokens to open connections. Set
+/// [`RecyclePolicy::max_lifetime`](crate::RecyclePolicy::max_lifetime) below
+/// the token lifetime, so connections are replaced before that.
+pub struct OAuth2Credentials {
+    config: OAuth2Config,
+    client: reqwest::Client,
+    /// Last token and when it has to be replaced, locked while fetching a
+    /// new one so concurrent connections don't request a token each.
+    cached: Mutex<Option<(String, Instant)>>,
+}
+
+/// Successful response of the token endpoint.
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+impl OAuth2Credentials {
+    /// Creates a new provider fetching tokens according to `config`.
+    #[must_use]
+    pub fn new(config: OAuth2Config) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Requests a new token from the token endpoint, returning it together
+    /// with when it has to be replaced, if it expires.
+    async fn fetch(&self) -> Result<(String, Option<Instant>), reqwest::Error> {
+        let mut form = vec![
+            ("grant_type", "client_credentials"),
+            ("client_id", self.config.client_id.as_str()),
+            ("client_secret", self.config.client_secret.as_str()),
+        ];
+        if let Some(scope) = &self.config.scope {
+            form.push(("scope", scope.as_str()));
+        }
+        let requested_at = Instant::now();
+        let response: TokenResponse = self
+            .client
+            .post(&self.config.token_url)
+            .form(&form)
+            .timeout(self.config.request_timeout)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let refresh_at = response.expires_in.map(|expires_in| {
+            requested_at
+                + Duration::from_secs(expires_in).saturating_sub(self.config.refresh_margin)
+        });
+        #[cfg(feature = "tracing")]
+        tracing::debug!(expires_in = response.expires_in, "fetched OAuth 2.0 token");
+        Ok((response.access_token, refresh_at))
+    }
+}
+
+#[async_trait]
+impl CredentialsProvider for OAuth2Credentials {
+    async fn credentials(&self) -> Result<Credentials, CredentialsError> {
+        let mut cached = self.cached.lock().await;
+        let token = match &*cached {
+            Some((token, refresh_at)) if Instant::now() < *refresh_at => token.clone(),
+            _ => {
+                let (token, refresh_at) = self.fetch().await?;
+                *cached = refresh_at.map(|refresh_at| (token.clone(), refresh_at));
+                token
+            }
+        };
+        Ok(Credentials {
+            username: self
+                .config
+                .username
+                .clone()
+                .unwrap_or_else(|| self.config.client_id.clone()),
+            password: token,
+        })
+    }
+}
+
+impl fmt::Debug for OAuth2Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OAuth2Credentials")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use crate::testing::{TokenServer, TokenServerConfig};
+
+    #[tokio::test]
+    async fn caches_token_until_refresh() {
+        let server = TokenServer::start("first").await.unwrap();
+        let provider = OAuth2Credentials::new(server.oauth2_config());
+        let credentials = provider.credentials().await.unwrap();
+        assert_eq!(credentials, Credentials::new("deadpool-amqprs", "first"));
+
+        server.set_access_token("second");
+        assert_eq!(provider.credentials().await.unwrap().password, "first");
+        assert_eq!(server.issued_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn refreshes_token_within_margin() {
+        let server = TokenServer::start("first").await.unwrap();
+        let provider = OAuth2Credentials::new(OAuth2Config {
+            refresh_margin: Duration::from_secs(3600),
+            ..server.oauth2_config()
+        });
+        assert_eq!(provider.credentials().await.unwrap().password, "first");
+
+        server.set_access_token("second");
+        assert_eq!(provider.credentials().await.unwrap().password, "second");
+        assert_eq!(server.issued_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn does_not_cache_tokens_without_expiry() {
+        let config = TokenServerConfig {
+            expires_in: None,
+            ..TokenServerConfig::default()
+        };
+        let server = TokenServer::start_with_config("token", config)
+            .await
+            .unwrap();
+        let provider = OAuth2Credentials::new(server.oauth2_config());
+        provider.credentials().await.unwrap();
+        provider.credentials().await.unwrap();
+        assert_eq!(server.issued_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn uses_configured_username() {
+        let server = TokenServer::start("token").await.unwrap();
+        let provider = OAuth2Credentials::new(OAuth2Config {
+            username: Some("orders".to_owned()),
+            ..server.oauth2_config()
+        });
+        assert_eq!(provider.credentials().await.unwrap().username, "orders");
+    }
+
+    #[tokio::test]
+    async fn fails_for_rejected_client() {
+        let server = TokenServer::start("token").await.unwrap();
+        let provider = OAuth2Credentials::new(OAuth2Config {
+            client_secret: "wrong".to_owned(),
+            ..server.oauth2_config()
+        });
+        assert!(provider.credentials().await.is_err());
+        assert_eq!(server.issued_count(), 0);
+    }
+}
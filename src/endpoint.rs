@@ -0,0 +1,356 @@
+//! Brokers a [`Manager`](crate::Manager) opens connections to.
+
+use std::{
+    borrow::Cow,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use amqprs::{connection::OpenConnectionArguments, security::SecurityCredentials};
+use tokio::sync::Notify;
+
+#[cfg(feature = "tls")]
+use crate::config::{ConfigError, TlsConfig};
+use crate::config::{ConnectionConfig, FailoverConfig, FailoverPolicy};
+use crate::connection::{ConnectionFactory, ManagedConnection};
+use crate::credentials::Credentials;
+use crate::Error;
+
+/// Broker a [`Manager`](crate::Manager) can open connections to.
+#[derive(Clone)]
+pub(crate) struct Endpoint {
+    args: OpenConnectionArguments,
+    connection_timeout: Option<Duration>,
+    /// Configuration `args` were built from, unknown for user provided
+    /// arguments.
+    connection: Option<ConnectionConfig>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<TlsConfig>>,
+}
+
+impl Endpoint {
+    /// Connects to this broker using `tls`, if given.
+    #[cfg(feature = "tls")]
+    #[must_use]
+    pub(crate) fn with_tls(mut self, tls: Option<&TlsConfig>) -> Self {
+        if let Some(tls) = tls {
+            self.tls = Some(Arc::new(tls.clone()));
+        }
+        self
+    }
+
+    /// Returns a span describing this broker, without credentials.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn span(&self) -> tracing::Span {
+        match &self.connection {
+            Some(c) => tracing::debug_span!(
+                "endpoint",
+                host = %c.host,
+                port = c.port,
+                vhost = %c.vhost,
+                connection_name = c.connection_name.as_deref(),
+            ),
+            None => tracing::debug_span!(
+                "endpoint",
+                host = self.args.get_host(),
+                port = self.args.get_port(),
+                vhost = self.args.get_virtual_host(),
+                connection_name = self.args.get_connection_name(),
+            ),
+        }
+    }
+
+    /// Returns an AMQP URI of this broker without credentials, see
+    /// [`ConnectionConfig::redacted_uri()`].
+    pub(crate) fn redacted_uri(&self) -> String {
+        let Some(connection) = &self.connection else {
+            return crate::config::redacted_args(&self.args);
+        };
+        #[cfg(feature = "tls")]
+        let tls = self.tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        let tls = false;
+        crate::config::redacted_uri(connection, tls)
+    }
+
+    /// Builds the TLS adaptor of connections to this broker.
+    ///
+    /// Blocks while reading certificates, see [`Endpoint::tls_adaptor()`] for
+    /// use within the runtime.
+    #[cfg(feature = "tls")]
+    fn blocking_tls_adaptor(
+        &self,
+        tls: &TlsConfig,
+    ) -> Result<amqprs::tls::TlsAdaptor, ConfigError> {
+        tls.adaptor(self.args.get_host()).map_err(ConfigError::Tls)
+    }
+
+    /// Builds the TLS adaptor of connections to this broker on the blocking
+    /// thread pool, as certificates may have to be read from disk.
+    #[cfg(feature = "tls")]
+    async fn tls_adaptor(
+        &self,
+        tls: &Arc<TlsConfig>,
+    ) -> Result<amqprs::tls::TlsAdaptor, ConfigError> {
+        let tls = Arc::clone(tls);
+        let host = self.args.get_host().to_owned();
+        tokio::task::spawn_blocking(move || tls.adaptor(&host))
+            .await
+            .unwrap_or_else(|e| Err(std::io::Error::other(e)))
+            .map_err(ConfigError::Tls)
+    }
+
+    /// Opens a new connection to this broker with `factory`, giving up after
+    /// the shorter of `attempt_timeout` and the configured connection timeout.
+    ///
+    /// Authenticates with `credentials` instead of the configured ones if
+    /// given.
+    pub(crate) async fn connect(
+        &self,
+        factory: &dyn ConnectionFactory,
+        evictions: &Arc<Notify>,
+        attempt_timeout: Option<Duration>,
+        credentials: Option<&Credentials>,
+    ) -> Result<ManagedConnection, Error> {
+        let mut args = Cow::Borrowed(&self.args);
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            let adaptor = self.tls_adaptor(tls).await.map_err(Error::Config)?;
+            args.to_mut().tls_adaptor(adaptor);
+        }
+        if let Some(credentials) = credentials {
+            args.to_mut().credentials(SecurityCredentials::new_plain(
+                &credentials.username,
+                &credentials.password,
+            ));
+        }
+
+        let timeout = match (self.connection_timeout, attempt_timeout) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        let opened = match timeout {
+            Some(timeout) => {
+                tokio::time::timeout(timeout, ManagedConnection::open(factory, &args, evictions))
+                    .await
+                    .map_err(|_| {
+                        Error::Connect(amqprs::error::Error::ConnectionOpenError(format!(
+                            "connection timed out after {timeout:?}"
+                        )))
+                    })?
+            }
+            None => ManagedConnection::open(factory, &args, evictions).await,
+        };
+        opened.map_err(Error::open)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.connection {
+            Some(c) => write!(f, "{}:{}", c.host, c.port),
+            None => write!(f, "{}:{}", self.args.get_host(), self.args.get_port()),
+        }
+    }
+}
+
+impl From<OpenConnectionArguments> for Endpoint {
+    fn from(args: OpenConnectionArguments) -> Self {
+        Self {
+            args,
+            connection_timeout: None,
+            connection: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+impl From<&ConnectionConfig> for Endpoint {
+    fn from(connection: &ConnectionConfig) -> Self {
+        Self {
+            args: connection.into(),
+            connection_timeout: connection.connection_timeout,
+            connection: Some(connection.clone()),
+            #[cfg(feature = "tls")]
+            tls: connection.tls.then(Arc::default),
+        }
+    }
+}
+
+/// Brokers of a [`Manager`](crate::Manager) and the order they are tried in.
+pub(crate) struct Endpoints {
+    endpoints: Vec<Endpoint>,
+    policy: FailoverPolicy,
+    cooldown: Duration,
+    /// Endpoint the next [`FailoverPolicy::RoundRobin`] attempt starts at.
+    next: AtomicUsize,
+    /// When each endpoint last failed to accept a connection.
+    failed_at: Mutex<Vec<Option<Instant>>>,
+}
+
+impl Endpoints {
+    pub(crate) fn new(endpoints: Vec<Endpoint>, failover: &FailoverConfig) -> Self {
+        assert!(!endpoints.is_empty(), "`Manager` requires an endpoint");
+        Self {
+            failed_at: Mutex::new(vec![None; endpoints.len()]),
+            endpoints,
+            policy: failover.policy,
+            cooldown: failover.cooldown,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the indices of all endpoints in the order they should be tried
+    /// for the next connection.
+    ///
+    /// Endpoints which failed within the cooldown are moved to the back, so
+    /// they are only tried once all others have failed as well.
+    pub(crate) fn attempt_order(&self) -> Vec<usize> {
+        let len = self.endpoints.len();
+        let mut order: Vec<usize> = match self.policy {
+            FailoverPolicy::Ordered => (0..len).collect(),
+            FailoverPolicy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+                (0..len).map(|i| (start + i) % len).collect()
+            }
+            FailoverPolicy::Random => {
+                let mut order: Vec<usize> = (0..len).collect();
+                fastrand::shuffle(&mut order);
+                order
+            }
+        };
+        let failed_at = self.failed_at.lock().unwrap();
+        // Stable, so the order chosen by the policy is kept within both groups.
+        order.sort_by_key(|&i| failed_at[i].is_some_and(|at| at.elapsed() < self.cooldown));
+        order
+    }
+
+    /// Checks that the TLS configuration of every endpoint can be loaded.
+    #[cfg(feature = "tls")]
+    pub(crate) fn check_tls(&self) -> Result<(), ConfigError> {
+        for endpoint in &self.endpoints {
+            if let Some(tls) = &endpoint.tls {
+                endpoint.blocking_tls_adaptor(tls)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the smallest [`ConnectionConfig::channel_max`] of all
+    /// endpoints, ignoring those without a limit.
+    pub(crate) fn channel_max(&self) -> Option<u16> {
+        self.endpoints
+            .iter()
+            .filter_map(|endpoint| endpoint.connection.as_ref()?.channel_max)
+            .filter(|&channel_max| channel_max != 0)
+            .min()
+    }
+
+    pub(crate) fn get(&self, index: usize) -> &Endpoint {
+        &self.endpoints[index]
+    }
+
+    pub(crate) fn record_success(&self, index: usize) {
+        self.failed_at.lock().unwrap()[index] = None;
+    }
+
+    pub(crate) fn record_failure(&self, index: usize) {
+        self.failed_at.lock().unwrap()[index] = Some(Instant::now());
+    }
+}
+
+/// Lists the redacted URIs of all brokers, separated by commas.
+impl fmt::Display for Endpoints {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, endpoint) in self.endpoints.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", endpoint.redacted_uri())?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Endpoints {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let uris: Vec<String> = self.endpoints.iter().map(Endpoint::redacted_uri).collect();
+        f.debug_struct("Endpoints")
+            .field("endpoints", &uris)
+            .field("policy", &self.policy)
+            .field("cooldown", &self.cooldown)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoints(len: usize, policy: FailoverPolicy, cooldown: Duration) -> Endpoints {
+        let endpoints = (0..len)
+            .map(|i| {
+                Endpoint::from(&ConnectionConfig {
+                    host: format!("node{i}"),
+                    ..ConnectionConfig::default()
+                })
+            })
+            .collect();
+        Endpoints::new(endpoints, &FailoverConfig { policy, cooldown })
+    }
+
+    #[test]
+    fn ordered_always_starts_at_first() {
+        let endpoints = endpoints(3, FailoverPolicy::Ordered, Duration::from_secs(60));
+        assert_eq!(endpoints.attempt_order(), [0, 1, 2]);
+        assert_eq!(endpoints.attempt_order(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn round_robin_rotates_start() {
+        let endpoints = endpoints(3, FailoverPolicy::RoundRobin, Duration::from_secs(60));
+        assert_eq!(endpoints.attempt_order(), [0, 1, 2]);
+        assert_eq!(endpoints.attempt_order(), [1, 2, 0]);
+        assert_eq!(endpoints.attempt_order(), [2, 0, 1]);
+        assert_eq!(endpoints.attempt_order(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn random_tries_every_endpoint_once() {
+        let endpoints = endpoints(5, FailoverPolicy::Random, Duration::from_secs(60));
+        let mut order = endpoints.attempt_order();
+        order.sort_unstable();
+        assert_eq!(order, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn failed_endpoints_are_tried_last() {
+        let endpoints = endpoints(3, FailoverPolicy::Ordered, Duration::from_secs(60));
+        endpoints.record_failure(0);
+        endpoints.record_failure(1);
+        assert_eq!(endpoints.attempt_order(), [2, 0, 1]);
+        endpoints.record_success(0);
+        assert_eq!(endpoints.attempt_order(), [0, 2, 1]);
+    }
+
+    #[test]
+    fn failures_expire_after_cooldown() {
+        let endpoints = endpoints(2, FailoverPolicy::Ordered, Duration::ZERO);
+        endpoints.record_failure(0);
+        assert_eq!(endpoints.attempt_order(), [0, 1]);
+    }
+
+    #[test]
+    fn lists_redacted_uris() {
+        let endpoints = endpoints(2, FailoverPolicy::Ordered, Duration::from_secs(60));
+        assert_eq!(
+            endpoints.to_string(),
+            "amqp://node0:5672/%2F, amqp://node1:5672/%2F"
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use std::fmt;
+
+use crate::{config::ConfigError, connection::ServerClose};
+
+/// Error returned by the [`Manager`](crate::Manager) when a connection
+/// couldn't be created or recycled.
+#[derive(Debug)]
+pub enum Error {
+    /// Connecting to the broker failed, e.g. because it is unreachable, the
+    /// connection timed out or the handshake failed.
+    Connect(amqprs::error::Error),
+    /// The broker refused the credentials (`403 ACCESS_REFUSED`).
+    AuthenticationFailed(String),
+    /// The broker refused access to the virtual host, or it doesn't exist
+    /// (`530 NOT_ALLOWED`).
+    VhostAccessRefused(String),
+    /// The broker closed the connection.
+    ServerClosed(ServerClose),
+    /// The connection was closed without the broker telling why.
+    Closed,
+    /// The test query of [`RecyclingMethod::Verified`] failed.
+    ///
+    /// [`RecyclingMethod::Verified`]: crate::config::RecyclingMethod::Verified
+    Probe(amqprs::error::Error),
+    /// The test query of [`RecyclingMethod::Verified`] didn't finish in time.
+    ///
+    /// [`RecyclingMethod::Verified`]: crate::config::RecyclingMethod::Verified
+    ProbeTimedOut,
+    /// The check of [`RecyclingMethod::Custom`] failed.
+    ///
+    /// [`RecyclingMethod::Custom`]: crate::config::RecyclingMethod::Custom
+    RecycleCheck(Box<dyn std::error::Error + Send + Sync>),
+    /// Declaring the [`Topology`](crate::topology::Topology) on a new
+    /// connection failed.
+    Topology(amqprs::error::Error),
+    /// The configuration is invalid, e.g. the TLS certificates can't be
+    /// loaded.
+    Config(ConfigError),
+    /// Opening a connection failed on more than one endpoint or attempt, see
+    /// [`RetryPolicy`](crate::config::RetryPolicy).
+    ///
+    /// Holds every failure in the order they happened, so never less than
+    /// two.
+    AttemptsFailed(Vec<FailedAttempt>),
+    /// The [`CredentialsProvider`](crate::credentials::CredentialsProvider)
+    /// failed to provide credentials.
+    Credentials(crate::credentials::CredentialsError),
+    /// No connection was opened because the circuit breaker is open, see
+    /// [`CircuitBreakerConfig`](crate::config::CircuitBreakerConfig).
+    CircuitOpen,
+}
+
+/// Failure to open a connection to one endpoint, see
+/// [`Error::AttemptsFailed`].
+#[derive(Debug)]
+pub struct FailedAttempt {
+    /// Broker the connection was opened to, as `host:port` if known.
+    pub endpoint: String,
+    /// Number of the attempt, starting at `1`.
+    pub attempt: u32,
+    /// Reason opening the connection failed.
+    pub error: Error,
+}
+
+impl Error {
+    /// Classifies an error returned while opening a connection.
+    ///
+    /// amqprs reports a `connection.close` received during the handshake as
+    /// a plain message, so refused credentials and vhosts are told apart by
+    /// the reply text the broker sent.
+    pub(crate) fn open(e: amqprs::error::Error) -> Self {
+        let message = e.to_string();
+        if message.contains("ACCESS_REFUSED") {
+            Self::AuthenticationFailed(message)
+        } else if message.contains("NOT_ALLOWED") {
+            Self::VhostAccessRefused(message)
+        } else {
+            Self::Connect(e)
+        }
+    }
+
+    /// Returns the error of the only failure in `failures`, or
+    /// [`Error::AttemptsFailed`] if there were several.
+    pub(crate) fn from_attempts(mut failures: Vec<FailedAttempt>) -> Self {
+        if failures.len() == 1 {
+            failures.remove(0).error
+        } else {
+            Self::AttemptsFailed(failures)
+        }
+    }
+
+    /// Returns whether trying again, e.g. with a new connection, may succeed.
+    ///
+    /// Refused credentials or vhosts, failed topology declarations and
+    /// invalid configurations require changes before trying again. Of the
+    /// connections closed by the broker only those closed with
+    /// `320 CONNECTION_FORCED`, e.g. during a node restart, are retryable.
+    /// [`Error::AttemptsFailed`] is retryable if its last failure is.
+    #[must_use]
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::AttemptsFailed(failures) => failures
+                .last()
+                .is_some_and(|failure| failure.error.is_retryable()),
+            Self::Connect(_)
+            | Self::Closed
+            | Self::Probe(_)
+            | Self::ProbeTimedOut
+            | Self::RecycleCheck(_)
+            | Self::Credentials(_)
+            | Self::CircuitOpen => true,
+            Self::ServerClosed(close) => close.reply_code == 320,
+            Self::AuthenticationFailed(_)
+            | Self::VhostAccessRefused(_)
+            | Self::Topology(_)
+            | Self::Config(_) => false,
+        }
+    }
+
+    /// Returns a short, stable name of the kind of this error for metrics
+    /// and logs.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub(crate) const fn kind(&self) -> &'static str {
+        match self {
+            Self::Connect(_) => "connect",
+            Self::AuthenticationFailed(_) => "authentication_failed",
+            Self::VhostAccessRefused(_) => "vhost_access_refused",
+            Self::ServerClosed(_) => "server_closed",
+            Self::Closed => "closed",
+            Self::Probe(_) => "probe",
+            Self::ProbeTimedOut => "probe_timed_out",
+            Self::RecycleCheck(_) => "recycle_check",
+            Self::Topology(_) => "topology",
+            Self::Config(_) => "config",
+            Self::AttemptsFailed(_) => "attempts_failed",
+            Self::Credentials(_) => "credentials",
+            Self::CircuitOpen => "circuit_open",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connect(e) => write!(f, "Connecting to the broker failed: {e}"),
+            Self::AuthenticationFailed(message) => write!(f, "Authentication failed: {message}"),
+            Self::VhostAccessRefused(message) => write!(f, "Vhost access refused: {message}"),
+            Self::ServerClosed(close) => write!(
+                f,
+                "Connection closed by the broker: {} {}",
+                close.reply_code, close.reply_text
+            ),
+            Self::Closed => write!(f, "Connection closed"),
+            Self::Probe(e) => write!(f, "Connection verification failed: {e}"),
+            Self::ProbeTimedOut => write!(f, "Connection verification timed out"),
+            Self::RecycleCheck(e) => write!(f, "Custom recycle check failed: {e}"),
+            Self::Topology(e) => write!(f, "Declaring topology failed: {e}"),
+            Self::Config(e) => write!(f, "Invalid configuration: {e}"),
+            Self::AttemptsFailed(failures) => {
+                write!(f, "All {} connection attempts failed", failures.len())?;
+                for (i, failure) in failures.iter().enumerate() {
+                    let separator = if i == 0 { ": " } else { "; " };
+                    write!(
+                        f,
+                        "{separator}{} (attempt {}): {}",
+                        failure.endpoint, failure.attempt, failure.error
+                    )?;
+                }
+                Ok(())
+            }
+            Self::Credentials(e) => write!(f, "Getting credentials failed: {e}"),
+            Self::CircuitOpen => write!(f, "Circuit breaker open, not connecting"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Connect(e) | Self::Probe(e) | Self::Topology(e) => Some(e),
+            Self::Config(e) => Some(e),
+            Self::RecycleCheck(e) | Self::Credentials(e) => Some(&**e),
+            Self::AttemptsFailed(failures) => failures
+                .last()
+                .map(|failure| &failure.error as &(dyn std::error::Error + 'static)),
+            Self::AuthenticationFailed(_)
+            | Self::VhostAccessRefused(_)
+            | Self::ServerClosed(_)
+            | Self::Closed
+            | Self::ProbeTimedOut
+            | Self::CircuitOpen => None,
+        }
+    }
+}
@@ -1,22 +1,65 @@
 #![doc = include_str!("../README.md")]
 #![allow(clippy::module_name_repetitions)]
 
+mod breaker;
+pub mod channel;
 pub mod config;
+pub mod connection;
+pub mod consumer;
+pub mod credentials;
+mod endpoint;
+mod error;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod publisher;
+mod recycle;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod topology;
+mod warmup;
+
+use std::{sync::Arc, time::Duration};
 
 pub use amqprs;
 use amqprs::connection::OpenConnectionArguments;
-use config::RecyclingMethod;
+use breaker::CircuitBreaker;
+use config::{RecyclingMethod, DEFAULT_CLOSE_TIMEOUT};
+use connection::Closer;
+use credentials::Credentials;
 pub use deadpool::managed::reexports::*;
-use deadpool::managed::{RecycleError, RecycleResult};
+use deadpool::managed::RecycleResult;
 use deadpool::{async_trait, managed};
+use endpoint::Endpoints;
+#[cfg(feature = "metrics")]
+use metrics::PoolMetrics;
+use tokio::sync::Notify;
+use topology::Topology;
+
+pub use breaker::CircuitState;
+pub use channel::{ChannelConfig, ChannelManager, ChannelPool};
+pub use config::{
+    CircuitBreakerConfig, Config, ConfigError, ConnectionConfig, FailoverConfig, FailoverPolicy,
+    RecyclePolicy, RetryPolicy, UrlError,
+};
+#[cfg(feature = "tls")]
+pub use config::{ClientIdentity, PemSource, TlsConfig};
+pub use connection::{
+    spawn_eviction_task, ConnectionFactory, DefaultConnectionFactory, ManagedConnection,
+};
+pub use consumer::{ConsumerConfig, ConsumerStream};
+pub use credentials::CredentialsProvider;
+#[cfg(feature = "oauth2")]
+pub use credentials::{OAuth2Config, OAuth2Credentials};
+pub use publisher::{PublisherManager, PublisherPool};
+pub use warmup::spawn_warmup_task;
 
-pub use config::{Config, ConfigError};
+pub use error::{Error, FailedAttempt};
 
 deadpool::managed_reexports!(
     "amqprs",
     Manager,
     managed::Object<Manager>,
-    amqprs::error::Error,
+    Error,
     ConfigError
 );
 
@@ -31,20 +74,308 @@ impl std::fmt::Debug for Placeholder {
 /// Type alias for [`Object`] in case Object isn't straight foward enough.
 pub type Connection = Object;
 
+/// Interval at which [`PoolExt::shutdown()`] checks whether all connections
+/// were returned.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Extension methods of [`Pool`].
+#[async_trait]
+pub trait PoolExt {
+    /// Retrieves a connection like [`Pool::get()`], additionally recording the
+    /// time spent waiting for it.
+    ///
+    /// This is the only place the checkout latency metric is recorded and the
+    /// `deadpool_amqprs::checkout` span is entered, as deadpool doesn't tell
+    /// the [`Manager`] about [`Pool::get()`]. Use it instead of
+    /// [`Pool::get()`] when the `metrics` or `tracing` feature is enabled.
+    ///
+    /// # Errors
+    ///
+    /// See [`PoolError`] for details.
+    async fn checkout(&self) -> Result<Connection, PoolError>;
+
+    /// Closes this pool and all of its connections gracefully.
+    ///
+    /// No more connections are handed out. Idle connections are closed right
+    /// away, checked out ones once they are returned. Resolves after all
+    /// connections were returned and the broker confirmed closing them, or
+    /// the close timed out, see [`Config::close_timeout`].
+    async fn shutdown(&self);
+}
+
+#[async_trait]
+impl PoolExt for Pool {
+    async fn checkout(&self) -> Result<Connection, PoolError> {
+        let checkout = async {
+            #[cfg(any(feature = "metrics", feature = "tracing"))]
+            let started = std::time::Instant::now();
+            let conn = self.get().await;
+            #[cfg(feature = "metrics")]
+            self.manager()
+                .pool_metrics()
+                .record_checkout(started.elapsed());
+            #[cfg(feature = "tracing")]
+            match &conn {
+                Ok(_) => tracing::debug!(waited = ?started.elapsed(), "connection checked out"),
+                Err(e) => {
+                    tracing::warn!(waited = ?started.elapsed(), error = %e, "checkout failed")
+                }
+            }
+            conn
+        };
+        #[cfg(feature = "tracing")]
+        let checkout = tracing::Instrument::instrument(checkout, {
+            let status = self.status();
+            tracing::debug_span!(
+                "deadpool_amqprs::checkout",
+                size = status.size,
+                available = status.available,
+                waiting = status.waiting,
+            )
+        });
+        checkout.await
+    }
+
+    async fn shutdown(&self) {
+        self.close();
+        while self.status().size > 0 {
+            tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+        }
+        self.manager().closer.wait().await;
+    }
+}
+
 /// [`Manager`] for creating and recycling [`amqprs`] connections.
 pub struct Manager {
-    con_args: OpenConnectionArguments,
+    endpoints: Endpoints,
+    factory: Arc<dyn ConnectionFactory>,
+    credentials: Option<Arc<dyn CredentialsProvider>>,
+    retry: RetryPolicy,
+    breaker: Option<CircuitBreaker>,
     recycling_method: RecyclingMethod,
+    recycle_timeout: Option<Duration>,
+    recycle_policy: RecyclePolicy,
+    closer: Closer,
+    topology: Option<Topology>,
+    #[cfg(feature = "metrics")]
+    metrics: Arc<PoolMetrics>,
+    /// Notified whenever the broker closes one of the created connections.
+    evictions: Arc<Notify>,
 }
 
 impl Manager {
     /// Creates a new [`Manager`] from the given arguments.
     #[must_use]
     #[inline]
-    pub const fn new(con_args: OpenConnectionArguments, recycling_method: RecyclingMethod) -> Self {
+    pub fn new(con_args: OpenConnectionArguments, recycling_method: RecyclingMethod) -> Self {
+        Self::from_endpoints(
+            Endpoints::new(vec![con_args.into()], &FailoverConfig::default()),
+            recycling_method,
+        )
+    }
+
+    /// Creates a new [`Manager`] opening connections to `endpoints`.
+    pub(crate) fn from_endpoints(endpoints: Endpoints, recycling_method: RecyclingMethod) -> Self {
         Self {
-            con_args,
+            endpoints,
+            factory: Arc::new(DefaultConnectionFactory),
+            credentials: None,
+            retry: RetryPolicy::ONCE,
+            breaker: None,
             recycling_method,
+            recycle_timeout: None,
+            recycle_policy: RecyclePolicy::UNLIMITED,
+            closer: Closer::new(DEFAULT_CLOSE_TIMEOUT),
+            topology: None,
+            #[cfg(feature = "metrics")]
+            metrics: Arc::default(),
+            evictions: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Returns the smallest `channel_max` configured for the endpoints.
+    pub(crate) fn channel_max(&self) -> Option<u16> {
+        self.endpoints.channel_max()
+    }
+
+    /// Opens connections with `factory` instead of [`DefaultConnectionFactory`].
+    #[must_use]
+    pub fn with_connection_factory(mut self, factory: Arc<dyn ConnectionFactory>) -> Self {
+        self.factory = factory;
+        self
+    }
+
+    /// Authenticates new connections with the credentials returned by
+    /// `provider` instead of the ones they were configured with.
+    #[must_use]
+    pub fn with_credentials_provider(mut self, provider: Arc<dyn CredentialsProvider>) -> Self {
+        self.credentials = Some(provider);
+        self
+    }
+
+    /// Tries opening connections according to `retry` instead of once.
+    #[must_use]
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Fails creating connections fast while opening them keeps failing, see
+    /// [`CircuitBreakerConfig`].
+    #[must_use]
+    pub fn with_circuit_breaker(mut self, circuit_breaker: Option<CircuitBreakerConfig>) -> Self {
+        self.breaker = circuit_breaker.as_ref().map(CircuitBreaker::new);
+        self
+    }
+
+    /// Limits how long verifying a connection may take when recycling it with
+    /// [`RecyclingMethod::Verified`] or [`RecyclingMethod::Custom`].
+    ///
+    /// Connections which can't be verified in time are discarded.
+    #[must_use]
+    pub fn with_recycle_timeout(mut self, recycle_timeout: Option<Duration>) -> Self {
+        self.recycle_timeout = recycle_timeout;
+        self
+    }
+
+    /// Discards connections exceeding the limits of `recycle_policy` instead
+    /// of recycling them.
+    #[must_use]
+    pub fn with_recycle_policy(mut self, recycle_policy: RecyclePolicy) -> Self {
+        self.recycle_policy = recycle_policy;
+        self
+    }
+
+    /// Limits how long closing a discarded connection may take.
+    ///
+    /// Connections are closed in the background, so this bounds how long
+    /// [`PoolExt::shutdown()`] waits for the broker to confirm the close.
+    #[must_use]
+    pub fn with_close_timeout(mut self, close_timeout: Duration) -> Self {
+        self.closer = Closer::new(close_timeout);
+        self
+    }
+
+    /// Declares `topology` on every connection this [`Manager`] creates.
+    #[must_use]
+    pub fn with_topology(mut self, topology: Option<Topology>) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    /// Returns the metrics recorded by this [`Manager`].
+    #[cfg(feature = "metrics")]
+    #[must_use]
+    pub fn pool_metrics(&self) -> &PoolMetrics {
+        &self.metrics
+    }
+
+    /// Returns the state of the circuit breaker, if enabled, e.g. for health
+    /// checks.
+    #[must_use]
+    pub fn circuit_state(&self) -> Option<CircuitState> {
+        self.breaker.as_ref().map(CircuitBreaker::state)
+    }
+
+    /// Opens a connection and declares the topology on it.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "deadpool_amqprs::create", skip_all, err)
+    )]
+    async fn create_connection(&self) -> Result<ManagedConnection, Error> {
+        if self
+            .breaker
+            .as_ref()
+            .is_some_and(|breaker| !breaker.allow())
+        {
+            return Err(Error::CircuitOpen);
+        }
+        let opened = self.open_connection().await;
+        if let Some(breaker) = &self.breaker {
+            breaker.record(opened.is_ok());
+        }
+        let mut conn = opened?;
+        conn.close_on_drop(self.closer.clone());
+        if let Some(topology) = &self.topology {
+            topology.apply(&conn).await.map_err(Error::Topology)?;
+        }
+        Ok(conn)
+    }
+
+    /// Opens a connection to the first endpoint accepting it, retrying
+    /// according to the [`RetryPolicy`].
+    async fn open_connection(&self) -> Result<ManagedConnection, Error> {
+        let mut failures = Vec::new();
+        for attempt in 1..=self.retry.max_attempts.max(1) {
+            if attempt > 1 {
+                let delay = self.retry.delay(attempt - 1);
+                #[cfg(feature = "tracing")]
+                tracing::debug!(attempt, ?delay, "retrying to open a connection");
+                tokio::time::sleep(delay).await;
+            }
+            let credentials = match self.credentials().await {
+                Ok(credentials) => credentials,
+                Err(error) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(error = %error, "getting credentials failed");
+                    failures.push(FailedAttempt {
+                        endpoint: "<credentials provider>".to_owned(),
+                        attempt,
+                        error,
+                    });
+                    continue;
+                }
+            };
+            for index in self.endpoints.attempt_order() {
+                let endpoint = self.endpoints.get(index);
+                #[cfg(feature = "tracing")]
+                let span = endpoint.span();
+                let connect = endpoint.connect(
+                    &*self.factory,
+                    &self.evictions,
+                    self.retry.attempt_timeout,
+                    credentials.as_ref(),
+                );
+                #[cfg(feature = "tracing")]
+                let connect = tracing::Instrument::instrument(connect, span.clone());
+                match connect.await {
+                    Ok(conn) => {
+                        #[cfg(feature = "tracing")]
+                        span.in_scope(|| tracing::debug!("connection opened"));
+                        self.endpoints.record_success(index);
+                        return Ok(conn);
+                    }
+                    Err(error) => {
+                        #[cfg(feature = "tracing")]
+                        span.in_scope(
+                            || tracing::warn!(error = %error, "connection attempt failed"),
+                        );
+                        self.endpoints.record_failure(index);
+                        failures.push(FailedAttempt {
+                            endpoint: endpoint.to_string(),
+                            attempt,
+                            error,
+                        });
+                    }
+                }
+            }
+            if !failures.last().is_some_and(|f| f.error.is_retryable()) {
+                break;
+            }
+        }
+        Err(Error::from_attempts(failures))
+    }
+
+    /// Returns the credentials of the next connection, if a
+    /// [`CredentialsProvider`] is configured.
+    async fn credentials(&self) -> Result<Option<Credentials>, Error> {
+        match &self.credentials {
+            Some(provider) => provider
+                .credentials()
+                .await
+                .map(Some)
+                .map_err(Error::Credentials),
+            None => Ok(None),
         }
     }
 }
@@ -52,22 +383,50 @@ impl Manager {
 impl std::fmt::Debug for Manager {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Manager")
-            .field("con_args", &Placeholder)
+            .field("endpoints", &self.endpoints)
+            .field("connection_factory", &Placeholder)
+            .field(
+                "credentials_provider",
+                &self.credentials.as_ref().map(|_| Placeholder),
+            )
+            .field("retry", &self.retry)
+            .field("circuit_state", &self.circuit_state())
+            .field("recycling_method", &self.recycling_method)
+            .field("recycle_timeout", &self.recycle_timeout)
+            .field("recycle_policy", &self.recycle_policy)
+            .field("close_timeout", &self.closer.timeout())
+            .field("topology", &self.topology)
             .finish()
     }
 }
 
+/// Shows the brokers connections are opened to, as redacted URIs, and the
+/// recycling method, e.g. `amqp://localhost:5672/%2F (fast recycling)`.
+impl std::fmt::Display for Manager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({} recycling)",
+            self.endpoints,
+            self.recycling_method.label()
+        )
+    }
+}
+
 #[async_trait]
 impl managed::Manager for Manager {
     /// Type of [`Object`]s that this [`Manager`] creates and recycles.
-    type Type = amqprs::connection::Connection;
+    type Type = ManagedConnection;
     /// Error that this [`Manager`] can return when creating and/or recycling
     /// [`Object`]s.
-    type Error = amqprs::error::Error;
+    type Error = Error;
 
     /// Creates a new instance of [`Manager::Type`].
     async fn create(&self) -> Result<Self::Type, Self::Error> {
-        Ok(Self::Type::open(&self.con_args).await?)
+        let created = self.create_connection().await;
+        #[cfg(feature = "metrics")]
+        self.metrics.record_create(&created);
+        created
     }
 
     /// Tries to recycle an instance of [`Manager::Type`].
@@ -75,16 +434,31 @@ impl managed::Manager for Manager {
     /// # Errors
     ///
     /// Returns [`Manager::Error`] if the instance couldn't be recycled.
-    async fn recycle(&self, conn: &mut Self::Type, _: &Metrics) -> RecycleResult<Self::Error> {
-        if conn.is_open() {
-            if self.recycling_method == RecyclingMethod::Verified
-                && conn.open_channel(None).await.is_err()
-            {
-                return Err(RecycleError::StaticMessage("Connection closed."));
-            }
-            Ok(())
-        } else {
-            Err(RecycleError::StaticMessage("Connection closed."))
+    async fn recycle(
+        &self,
+        conn: &mut Self::Type,
+        metrics: &Metrics,
+    ) -> RecycleResult<Self::Error> {
+        let checked = recycle::check(
+            conn,
+            metrics,
+            &self.recycling_method,
+            self.recycle_timeout,
+            &self.recycle_policy,
+        )
+        .await;
+        #[cfg(feature = "metrics")]
+        self.metrics
+            .record_recycle(&self.recycling_method, &checked);
+        #[cfg(feature = "tracing")]
+        if let Err(rejection) = &checked {
+            tracing::debug!(
+                recycling_method = ?self.recycling_method,
+                reason = rejection.label(),
+                details = ?rejection,
+                "connection discarded",
+            );
         }
+        checked.map_err(Into::into)
     }
 }
@@ -0,0 +1,354 @@
+//! Metrics of a [`Pool`] in the Prometheus text format.
+//!
+//! The [`Manager`](crate::Manager) of every pool records connection creates,
+//! create errors and recycle outcomes in its [`PoolMetrics`]. The current
+//! size of the pool is read from [`Pool::status()`] and the state of the
+//! circuit breaker from [`Manager::circuit_state()`] when rendering.
+//!
+//! # Checkout latency
+//!
+//! deadpool doesn't tell the manager how long a caller waited, so the time
+//! spent waiting for a connection is only recorded by
+//! [`PoolExt::checkout()`], which [`ChannelPool`](crate::channel::ChannelPool)s
+//! and consumers use as well. Connections retrieved with [`Pool::get()`] or
+//! [`Pool::timeout_get()`] directly are missing from the
+//! `checkout_duration_seconds` histogram.
+//!
+//! # Example
+//!
+//! ```rs
+//! use deadpool_amqprs::{metrics, PoolExt};
+//!
+//! let conn = pool.checkout().await.unwrap();
+//!
+//! // Serve this from your `/metrics` endpoint.
+//! let text = metrics::render(&pool, "orders");
+//! ```
+//!
+//! [`PoolExt::checkout()`]: crate::PoolExt::checkout
+//! [`Manager::circuit_state()`]: crate::Manager::circuit_state
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{config::RecyclingMethod, recycle::Rejection, CircuitState, Error, Pool};
+
+/// Upper bounds of the checkout latency histogram buckets in seconds.
+const CHECKOUT_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counters and histograms recorded by the [`Manager`](crate::Manager) of a
+/// [`Pool`].
+#[derive(Debug, Default)]
+pub struct PoolMetrics {
+    creates: AtomicU64,
+    create_errors: Mutex<BTreeMap<&'static str, u64>>,
+    recycles: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    checkouts: Histogram,
+}
+
+impl PoolMetrics {
+    /// Returns the number of connections created.
+    #[must_use]
+    pub fn creates(&self) -> u64 {
+        self.creates.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_create<T>(&self, result: &Result<T, Error>) {
+        match result {
+            Ok(_) => {
+                self.creates.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                *self
+                    .create_errors
+                    .lock()
+                    .unwrap()
+                    .entry(e.kind())
+                    .or_default() += 1;
+            }
+        }
+    }
+
+    pub(crate) fn record_recycle(
+        &self,
+        recycling_method: &RecyclingMethod,
+        result: &Result<(), Rejection>,
+    ) {
+        let outcome = match result {
+            Ok(()) => "recycled",
+            Err(rejection) => rejection.label(),
+        };
+        *self
+            .recycles
+            .lock()
+            .unwrap()
+            .entry((recycling_method.label(), outcome))
+            .or_default() += 1;
+    }
+
+    pub(crate) fn record_checkout(&self, duration: Duration) {
+        self.checkouts.observe(duration);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; CHECKOUT_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(CHECKOUT_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(
+            u64::try_from(duration.as_micros()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// Renders the metrics of `pool` in the Prometheus text format.
+///
+/// Every sample is labeled with `pool="<name>"`.
+#[must_use]
+pub fn render(pool: &Pool, name: &str) -> String {
+    let metrics = pool.manager().pool_metrics();
+    let status = pool.status();
+    let pool_label = format!("pool=\"{}\"", escape(name));
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "connections_created_total",
+        "counter",
+        "Connections created.",
+    );
+    sample(
+        &mut out,
+        "connections_created_total",
+        &pool_label,
+        metrics.creates(),
+    );
+
+    header(
+        &mut out,
+        "connection_create_errors_total",
+        "counter",
+        "Connections which couldn't be created, by kind of error.",
+    );
+    for (kind, count) in metrics.create_errors.lock().unwrap().iter() {
+        let labels = format!("{pool_label},kind=\"{kind}\"");
+        sample(&mut out, "connection_create_errors_total", &labels, count);
+    }
+
+    header(
+        &mut out,
+        "recycles_total",
+        "counter",
+        "Connections recycled or discarded, by recycling method and outcome.",
+    );
+    for ((method, outcome), count) in metrics.recycles.lock().unwrap().iter() {
+        let labels = format!("{pool_label},method=\"{method}\",outcome=\"{outcome}\"");
+        sample(&mut out, "recycles_total", &labels, count);
+    }
+
+    header(
+        &mut out,
+        "checkout_duration_seconds",
+        "histogram",
+        "Time spent waiting for a connection in PoolExt::checkout.",
+    );
+    let checkouts = &metrics.checkouts;
+    for (bucket, bound) in checkouts.buckets.iter().zip(CHECKOUT_BUCKETS) {
+        let labels = format!("{pool_label},le=\"{bound}\"");
+        sample(
+            &mut out,
+            "checkout_duration_seconds_bucket",
+            &labels,
+            bucket.load(Ordering::Relaxed),
+        );
+    }
+    let count = checkouts.count.load(Ordering::Relaxed);
+    let labels = format!("{pool_label},le=\"+Inf\"");
+    sample(&mut out, "checkout_duration_seconds_bucket", &labels, count);
+    #[allow(clippy::cast_precision_loss)]
+    let sum = checkouts.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+    sample(&mut out, "checkout_duration_seconds_sum", &pool_label, sum);
+    sample(
+        &mut out,
+        "checkout_duration_seconds_count",
+        &pool_label,
+        count,
+    );
+
+    for (metric, help, value) in [
+        (
+            "pool_max_size",
+            "Maximum number of connections.",
+            status.max_size,
+        ),
+        ("pool_size", "Current number of connections.", status.size),
+        ("pool_available", "Idle connections.", status.available),
+        (
+            "pool_waiting",
+            "Tasks waiting for a connection.",
+            status.waiting,
+        ),
+    ] {
+        header(&mut out, metric, "gauge", help);
+        sample(&mut out, metric, &pool_label, value);
+    }
+
+    if let Some(current) = pool.manager().circuit_state() {
+        header(
+            &mut out,
+            "circuit_breaker_state",
+            "gauge",
+            "State of the circuit breaker, 1 for the current state.",
+        );
+        for state in [
+            CircuitState::Closed,
+            CircuitState::Open,
+            CircuitState::HalfOpen,
+        ] {
+            let labels = format!("{pool_label},state=\"{}\"", state.label());
+            sample(
+                &mut out,
+                "circuit_breaker_state",
+                &labels,
+                u8::from(state == current),
+            );
+        }
+    }
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP deadpool_amqprs_{name} {help}");
+    let _ = writeln!(out, "# TYPE deadpool_amqprs_{name} {kind}");
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "deadpool_amqprs_{name}{{{labels}}} {value}");
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use amqprs::connection::OpenConnectionArguments;
+
+    use super::*;
+    use crate::{CircuitBreakerConfig, Manager};
+
+    fn pool(circuit_breaker: Option<CircuitBreakerConfig>) -> Pool {
+        let manager = Manager::new(
+            OpenConnectionArguments::new("localhost", 5672, "guest", "guest"),
+            RecyclingMethod::Fast,
+        )
+        .with_circuit_breaker(circuit_breaker);
+        Pool::builder(manager).max_size(4).build().unwrap()
+    }
+
+    /// Returns the value of the sample `name` with `labels`.
+    fn value<'a>(text: &'a str, name: &str, labels: &str) -> &'a str {
+        let prefix = format!("deadpool_amqprs_{name}{{{labels}}} ");
+        text.lines()
+            .find_map(|line| line.strip_prefix(&prefix))
+            .unwrap_or_else(|| panic!("no sample {prefix:?} in\n{text}"))
+    }
+
+    #[test]
+    fn renders_text_format() {
+        let pool = pool(None);
+        let metrics = pool.manager().pool_metrics();
+        metrics.record_create(&Ok(()));
+        metrics.record_create::<()>(&Err(Error::Closed));
+        metrics.record_recycle(&RecyclingMethod::Fast, &Ok(()));
+        metrics.record_recycle(&RecyclingMethod::Verified, &Err(Rejection::UsesExceeded));
+
+        let text = render(&pool, "orders");
+        assert!(text.contains(
+            "# HELP deadpool_amqprs_connections_created_total Connections created.\n\
+             # TYPE deadpool_amqprs_connections_created_total counter\n\
+             deadpool_amqprs_connections_created_total{pool=\"orders\"} 1\n"
+        ));
+        let errors = "pool=\"orders\",kind=\"closed\"";
+        assert_eq!(value(&text, "connection_create_errors_total", errors), "1");
+        let recycled = "pool=\"orders\",method=\"fast\",outcome=\"recycled\"";
+        assert_eq!(value(&text, "recycles_total", recycled), "1");
+        let discarded = "pool=\"orders\",method=\"verified\",outcome=\"max_uses\"";
+        assert_eq!(value(&text, "recycles_total", discarded), "1");
+        assert_eq!(value(&text, "pool_max_size", "pool=\"orders\""), "4");
+        assert_eq!(value(&text, "pool_size", "pool=\"orders\""), "0");
+        assert!(!text.contains("circuit_breaker_state"));
+
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            let (series, value) = line.rsplit_once(' ').unwrap();
+            assert!(series.starts_with("deadpool_amqprs_"), "{line}");
+            assert!(series.ends_with('}'), "{line}");
+            assert!(value.parse::<f64>().is_ok(), "{line}");
+        }
+    }
+
+    #[test]
+    fn renders_circuit_state() {
+        let text = render(&pool(Some(CircuitBreakerConfig::default())), "orders");
+        let state = |state| value(&text, "circuit_breaker_state", state);
+        assert_eq!(state("pool=\"orders\",state=\"closed\""), "1");
+        assert_eq!(state("pool=\"orders\",state=\"open\""), "0");
+        assert_eq!(state("pool=\"orders\",state=\"half_open\""), "0");
+    }
+
+    #[test]
+    fn escapes_pool_name() {
+        let text = render(&pool(None), "a\"b\\c\nd");
+        assert_eq!(value(&text, "pool_size", "pool=\"a\\\"b\\\\c\\nd\""), "0");
+    }
+
+    #[test]
+    fn renders_cumulative_histogram_buckets() {
+        let pool = pool(None);
+        let metrics = pool.manager().pool_metrics();
+        for millis in [3, 30, 20_000] {
+            metrics.record_checkout(Duration::from_millis(millis));
+        }
+
+        let text = render(&pool, "orders");
+        let bucket = |le| {
+            let labels = format!("pool=\"orders\",le=\"{le}\"");
+            value(&text, "checkout_duration_seconds_bucket", &labels).to_owned()
+        };
+        assert_eq!(bucket("0.001"), "0");
+        assert_eq!(bucket("0.005"), "1");
+        assert_eq!(bucket("0.025"), "1");
+        assert_eq!(bucket("0.05"), "2");
+        assert_eq!(bucket("10"), "2");
+        assert_eq!(bucket("+Inf"), "3");
+        let count = value(&text, "checkout_duration_seconds_count", "pool=\"orders\"");
+        assert_eq!(count, "3");
+        let sum = value(&text, "checkout_duration_seconds_sum", "pool=\"orders\"");
+        assert_eq!(sum, "20.033");
+    }
+}
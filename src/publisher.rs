@@ -0,0 +1,332 @@
+//! Pooling of channels in publisher confirm mode.
+//!
+//! A [`PublisherPool`] hands out [`ConfirmChannel`]s, which have
+//! `confirm.select` enabled and correlate the broker's `basic.ack` and
+//! `basic.nack` with the messages published on them. The future returned by
+//! [`ConfirmChannel::publish()`] resolves once the broker confirmed the
+//! message, or fails if the channel dies first, which gives at-least-once
+//! publishing when failed messages are retried.
+//!
+//! # Example
+//!
+//! ```rs
+//! use deadpool_amqprs::{ChannelConfig, Config};
+//! use amqprs::{channel::BasicPublishArguments, BasicProperties};
+//!
+//! let pool = Config::from_url("amqp://localhost").unwrap().create_pool();
+//! let publishers = ChannelConfig::default().create_publisher_pool(pool);
+//!
+//! let publisher = publishers.get().await.unwrap();
+//! let confirmation = publisher
+//!     .publish(
+//!         BasicPublishArguments::new("orders", "order.created"),
+//!         BasicProperties::default(),
+//!         b"{}".to_vec(),
+//!     )
+//!     .await
+//!     .unwrap();
+//! assert!(confirmation.is_ack());
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+use amqprs::{
+    callbacks::ChannelCallback,
+    channel::{BasicPublishArguments, Channel, ConfirmSelectArguments},
+    Ack, BasicProperties, Cancel, CloseChannel, Nack, Return,
+};
+use deadpool::managed::{self, RecycleError, RecycleResult};
+use deadpool::{async_trait, Runtime};
+use tokio::sync::oneshot;
+
+use crate::{
+    channel::{ChannelConfig, ChannelError, ChannelManager, PooledChannel},
+    Metrics, Pool,
+};
+
+/// Type alias for using [`deadpool::managed::Pool`] with [`PublisherManager`].
+pub type PublisherPool = managed::Pool<PublisherManager>;
+
+/// Type alias for using [`deadpool::managed::PoolBuilder`] with [`PublisherManager`].
+pub type PublisherPoolBuilder = managed::PoolBuilder<PublisherManager>;
+
+/// Type alias for using [`deadpool::managed::PoolError`] with [`PublisherManager`].
+pub type PublisherPoolError = managed::PoolError<ChannelError>;
+
+impl ChannelConfig {
+    /// Creates a new [`PublisherPool`] opening its channels on connections
+    /// from `pool`.
+    #[must_use]
+    pub fn create_publisher_pool(&self, pool: Pool) -> PublisherPool {
+        self.publisher_builder(pool)
+            .build()
+            .expect("`PublisherPoolBuilder::build` errored when it shouldn't")
+    }
+
+    /// Returns a [`PublisherPoolBuilder`] opening its channels on connections
+    /// from `pool`.
+    pub fn publisher_builder(&self, pool: Pool) -> PublisherPoolBuilder {
+        PublisherPool::builder(PublisherManager::new(pool, self.channels_per_connection))
+            .config(self.pool_config.unwrap_or_default())
+            .runtime(Runtime::Tokio1)
+    }
+}
+
+/// Outcome of publishing a message on a [`ConfirmChannel`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Confirmation {
+    /// The broker took responsibility for the message.
+    Ack,
+    /// The broker couldn't take responsibility for the message, it should be
+    /// published again.
+    Nack,
+}
+
+impl Confirmation {
+    /// Returns whether the broker took responsibility for the message.
+    #[must_use]
+    pub const fn is_ack(self) -> bool {
+        matches!(self, Self::Ack)
+    }
+}
+
+/// Error returned when a message couldn't be published or its confirmation
+/// wasn't received.
+#[derive(Debug)]
+pub enum PublishError {
+    /// Sending the message failed.
+    Amqp(amqprs::error::Error),
+    /// The channel closed before the broker confirmed the message, so it may
+    /// or may not have been received.
+    ChannelClosed,
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Amqp(e) => write!(f, "Publishing failed: {e}"),
+            Self::ChannelClosed => write!(f, "Channel closed before the message was confirmed"),
+        }
+    }
+}
+
+impl std::error::Error for PublishError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Amqp(e) => Some(e),
+            Self::ChannelClosed => None,
+        }
+    }
+}
+
+/// Confirmations outstanding on a channel.
+///
+/// Only the channel's callback holds a strong reference, so once amqprs drops
+/// the callback of a dead channel all outstanding senders are dropped and the
+/// waiting publishers fail with [`PublishError::ChannelClosed`].
+#[derive(Default)]
+struct Confirms {
+    state: Mutex<ConfirmsState>,
+}
+
+struct ConfirmsState {
+    /// Delivery tag the broker assigns to the next published message.
+    next_tag: u64,
+    pending: BTreeMap<u64, oneshot::Sender<Confirmation>>,
+}
+
+impl Default for ConfirmsState {
+    fn default() -> Self {
+        Self {
+            next_tag: 1,
+            pending: BTreeMap::new(),
+        }
+    }
+}
+
+impl Confirms {
+    fn register(&self) -> (u64, oneshot::Receiver<Confirmation>) {
+        let (sender, receiver) = oneshot::channel();
+        let mut state = self.state.lock().unwrap();
+        let tag = state.next_tag;
+        state.next_tag += 1;
+        state.pending.insert(tag, sender);
+        (tag, receiver)
+    }
+
+    fn resolve(&self, tag: u64, multiple: bool, confirmation: Confirmation) {
+        let mut state = self.state.lock().unwrap();
+        if multiple {
+            let remaining = state.pending.split_off(&(tag + 1));
+            for (_, sender) in std::mem::replace(&mut state.pending, remaining) {
+                let _ = sender.send(confirmation);
+            }
+        } else if let Some(sender) = state.pending.remove(&tag) {
+            let _ = sender.send(confirmation);
+        }
+    }
+
+    fn fail_all(&self) {
+        self.state.lock().unwrap().pending.clear();
+    }
+}
+
+struct ConfirmCallback {
+    confirms: Arc<Confirms>,
+}
+
+#[async_trait]
+impl ChannelCallback for ConfirmCallback {
+    async fn close(&mut self, _: &Channel, _: CloseChannel) -> Result<(), amqprs::error::Error> {
+        self.confirms.fail_all();
+        Ok(())
+    }
+
+    async fn cancel(&mut self, _: &Channel, _: Cancel) -> Result<(), amqprs::error::Error> {
+        Ok(())
+    }
+
+    async fn flow(&mut self, _: &Channel, active: bool) -> Result<bool, amqprs::error::Error> {
+        Ok(active)
+    }
+
+    async fn publish_ack(&mut self, _: &Channel, ack: Ack) {
+        self.confirms
+            .resolve(ack.delivery_tag(), ack.mutiple(), Confirmation::Ack);
+    }
+
+    async fn publish_nack(&mut self, _: &Channel, nack: Nack) {
+        self.confirms
+            .resolve(nack.delivery_tag(), nack.multiple(), Confirmation::Nack);
+    }
+
+    async fn publish_return(&mut self, _: &Channel, _: Return, _: BasicProperties, _: Vec<u8>) {}
+}
+
+/// Channel in publisher confirm mode handed out by a [`PublisherPool`].
+///
+/// Dereferences to [`Channel`], but messages published directly on the
+/// channel instead of with [`ConfirmChannel::publish()`] break the
+/// correlation of confirmations.
+pub struct ConfirmChannel {
+    channel: PooledChannel,
+    confirms: Weak<Confirms>,
+    /// Serializes publishes, so delivery tags are assigned in the order the
+    /// broker receives the messages.
+    publish_lock: tokio::sync::Mutex<()>,
+    /// Set once publishing failed and the delivery tags may be out of sync.
+    broken: AtomicBool,
+}
+
+impl ConfirmChannel {
+    /// Publishes a message and waits for the broker to confirm it.
+    ///
+    /// Messages are sent in the order this method is first polled, while
+    /// their confirmations are awaited concurrently, so many publishes may be
+    /// in flight on the same channel.
+    ///
+    /// Messages returned as unroutable are confirmed as well, use the
+    /// `mandatory` flag only if the [`Channel`]'s returns are handled
+    /// elsewhere.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`PublishError`] if sending the message failed or the channel
+    /// closed before the broker confirmed it.
+    pub async fn publish(
+        &self,
+        args: BasicPublishArguments,
+        properties: BasicProperties,
+        content: Vec<u8>,
+    ) -> Result<Confirmation, PublishError> {
+        let receiver = {
+            let _guard = self.publish_lock.lock().await;
+            let confirms = match self.confirms.upgrade() {
+                Some(confirms) if !self.broken.load(Ordering::Relaxed) => confirms,
+                _ => return Err(PublishError::ChannelClosed),
+            };
+            let (_, receiver) = confirms.register();
+            drop(confirms);
+            // The delivery tag is used up, so if this future is dropped
+            // before the message was sent the tags are out of sync.
+            self.broken.store(true, Ordering::Relaxed);
+            self.channel
+                .basic_publish(properties, content, args)
+                .await
+                .map_err(PublishError::Amqp)?;
+            self.broken.store(false, Ordering::Relaxed);
+            receiver
+        };
+        receiver.await.map_err(|_| PublishError::ChannelClosed)
+    }
+}
+
+impl Deref for ConfirmChannel {
+    type Target = Channel;
+
+    fn deref(&self) -> &Channel {
+        &self.channel
+    }
+}
+
+/// [`Manager`] for creating and recycling [`ConfirmChannel`]s.
+///
+/// [`Manager`]: managed::Manager
+#[derive(Debug)]
+pub struct PublisherManager {
+    channels: ChannelManager,
+}
+
+impl PublisherManager {
+    /// Creates a new [`PublisherManager`] opening at most
+    /// `channels_per_connection` channels on each connection from `pool`.
+    #[must_use]
+    pub fn new(pool: Pool, channels_per_connection: usize) -> Self {
+        Self {
+            channels: ChannelManager::new(pool, channels_per_connection),
+        }
+    }
+}
+
+#[async_trait]
+impl managed::Manager for PublisherManager {
+    type Type = ConfirmChannel;
+    type Error = ChannelError;
+
+    async fn create(&self) -> Result<Self::Type, Self::Error> {
+        let channel = managed::Manager::create(&self.channels).await?;
+        channel
+            .confirm_select(ConfirmSelectArguments::default())
+            .await?;
+        let confirms = Arc::new(Confirms::default());
+        let weak = Arc::downgrade(&confirms);
+        channel
+            .register_callback(ConfirmCallback { confirms })
+            .await?;
+        Ok(ConfirmChannel {
+            channel,
+            confirms: weak,
+            publish_lock: tokio::sync::Mutex::new(()),
+            broken: AtomicBool::new(false),
+        })
+    }
+
+    async fn recycle(
+        &self,
+        channel: &mut Self::Type,
+        metrics: &Metrics,
+    ) -> RecycleResult<Self::Error> {
+        if channel.broken.load(Ordering::Relaxed) || channel.confirms.strong_count() == 0 {
+            return Err(RecycleError::StaticMessage("Confirmations out of sync."));
+        }
+        managed::Manager::recycle(&self.channels, &mut channel.channel, metrics).await
+    }
+}
@@ -0,0 +1,270 @@
+//! Checks run by the [`Manager`](crate::Manager) when recycling connections.
+
+use std::{future::Future, time::Duration};
+
+use amqprs::{channel::ExchangeDeclareArguments, connection::Connection};
+use deadpool::managed::{Metrics, RecycleError};
+
+use crate::{
+    config::{RecyclePolicy, RecyclingMethod},
+    connection::{ManagedConnection, ServerClose},
+    Error,
+};
+
+/// Reason a connection was discarded instead of being recycled.
+#[derive(Debug)]
+pub(crate) enum Rejection {
+    /// The connection isn't open anymore.
+    Closed,
+    /// The broker closed the connection.
+    ServerClosed(ServerClose),
+    /// The test query of [`RecyclingMethod::Verified`] failed.
+    VerificationFailed(amqprs::error::Error),
+    /// The test query of [`RecyclingMethod::Verified`] or the check of
+    /// [`RecyclingMethod::Custom`] didn't finish in time.
+    VerificationTimedOut,
+    /// The check of [`RecyclingMethod::Custom`] failed.
+    CheckFailed(Box<dyn std::error::Error + Send + Sync>),
+    /// The connection is older than [`RecyclePolicy::max_lifetime`].
+    LifetimeExceeded,
+    /// The connection was last handed out longer than
+    /// [`RecyclePolicy::max_since_checkout`] ago.
+    SinceCheckoutExceeded,
+    /// The connection was handed out [`RecyclePolicy::max_uses`] times.
+    UsesExceeded,
+}
+
+impl Rejection {
+    /// Returns a short, stable name of this rejection for metrics and logs.
+    #[cfg_attr(not(any(feature = "metrics", feature = "tracing")), allow(dead_code))]
+    pub(crate) const fn label(&self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::ServerClosed(_) => "server_closed",
+            Self::VerificationFailed(_) => "verification_failed",
+            Self::VerificationTimedOut => "verification_timed_out",
+            Self::CheckFailed(_) => "check_failed",
+            Self::LifetimeExceeded => "max_lifetime",
+            Self::SinceCheckoutExceeded => "max_since_checkout",
+            Self::UsesExceeded => "max_uses",
+        }
+    }
+}
+
+impl From<Rejection> for RecycleError<Error> {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            Rejection::Closed => Self::Backend(Error::Closed),
+            Rejection::ServerClosed(close) => Self::Backend(Error::ServerClosed(close)),
+            Rejection::VerificationFailed(e) => Self::Backend(Error::Probe(e)),
+            Rejection::VerificationTimedOut => Self::Backend(Error::ProbeTimedOut),
+            Rejection::CheckFailed(e) => Self::Backend(Error::RecycleCheck(e)),
+            Rejection::LifetimeExceeded => {
+                Self::StaticMessage("Connection exceeded its maximum lifetime.")
+            }
+            Rejection::SinceCheckoutExceeded => {
+                Self::StaticMessage("Connection wasn't handed out for too long.")
+            }
+            Rejection::UsesExceeded => Self::StaticMessage("Connection exceeded its maximum uses."),
+        }
+    }
+}
+
+/// Checks whether `conn` can be handed out again.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "deadpool_amqprs::recycle",
+        level = "debug",
+        skip_all,
+        fields(recycling_method = ?recycling_method),
+    )
+)]
+pub(crate) async fn check(
+    conn: &ManagedConnection,
+    metrics: &Metrics,
+    recycling_method: &RecyclingMethod,
+    recycle_timeout: Option<Duration>,
+    recycle_policy: &RecyclePolicy,
+) -> Result<(), Rejection> {
+    if !conn.is_open() {
+        return Err(Rejection::Closed);
+    }
+    if let Some(close) = conn.state().server_close() {
+        return Err(Rejection::ServerClosed(close));
+    }
+    check_limits(recycle_policy, metrics, conn.jitter())?;
+    match recycling_method {
+        RecyclingMethod::Fast => Ok(()),
+        RecyclingMethod::Verified => within(recycle_timeout, verify_connection(conn))
+            .await?
+            .map_err(Rejection::VerificationFailed),
+        RecyclingMethod::Custom(check) => within(recycle_timeout, check(conn, metrics))
+            .await?
+            .map_err(Rejection::CheckFailed),
+    }
+}
+
+/// Awaits `future`, giving up after `timeout` if set.
+async fn within<F: Future>(timeout: Option<Duration>, future: F) -> Result<F::Output, Rejection> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| Rejection::VerificationTimedOut),
+        None => Ok(future.await),
+    }
+}
+
+/// Checks the limits of `policy`, lowered by `jitter` times
+/// [`RecyclePolicy::jitter`].
+///
+/// The connection was handed out once when created and once per successful
+/// recycle, so `recycle_count + 1` times before this checkout.
+fn check_limits(policy: &RecyclePolicy, metrics: &Metrics, jitter: f64) -> Result<(), Rejection> {
+    let scale = 1.0 - policy.jitter.clamp(0.0, 1.0) * jitter;
+    // `clamp` passes NaN through, which `Duration::mul_f64` panics on.
+    let scale = if scale.is_nan() { 1.0 } else { scale };
+    if let Some(max_lifetime) = policy.max_lifetime {
+        if metrics.age() >= max_lifetime.mul_f64(scale) {
+            return Err(Rejection::LifetimeExceeded);
+        }
+    }
+    if let Some(max_since_checkout) = policy.max_since_checkout {
+        if metrics.last_used() >= max_since_checkout.mul_f64(scale) {
+            return Err(Rejection::SinceCheckoutExceeded);
+        }
+    }
+    if let Some(max_uses) = policy.max_uses {
+        #[allow(
+            clippy::cast_possible_truncation,
+            clippy::cast_precision_loss,
+            clippy::cast_sign_loss
+        )]
+        let max_uses = (max_uses as f64 * scale).ceil() as usize;
+        if metrics.recycle_count + 1 >= max_uses {
+            return Err(Rejection::UsesExceeded);
+        }
+    }
+    Ok(())
+}
+
+/// Opens a probe channel on `conn`, passively declares `amq.direct` on it and
+/// closes it again.
+async fn verify_connection(conn: &Connection) -> Result<(), amqprs::error::Error> {
+    let channel = conn.open_channel(None).await?;
+    let declared = channel
+        .exchange_declare(
+            ExchangeDeclareArguments::new("amq.direct", "direct")
+                .passive(true)
+                .finish(),
+        )
+        .await;
+    // Always close the probe channel so its number is released, even if the
+    // declare failed.
+    let closed = channel.close().await;
+    declared.and(closed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    /// Metrics of a connection opened `age` ago, last handed out
+    /// `since_checkout` ago and recycled `recycle_count` times.
+    fn metrics(age: Duration, since_checkout: Duration, recycle_count: usize) -> Metrics {
+        let now = Instant::now();
+        Metrics {
+            created: now - age,
+            recycled: Some(now - since_checkout),
+            recycle_count,
+        }
+    }
+
+    #[test]
+    fn unlimited_policy_accepts_everything() {
+        let metrics = metrics(60 * MINUTE, 60 * MINUTE, 1000);
+        assert!(check_limits(&RecyclePolicy::UNLIMITED, &metrics, 1.0).is_ok());
+    }
+
+    #[test]
+    fn rejects_connections_past_max_lifetime() {
+        let policy = RecyclePolicy {
+            max_lifetime: Some(5 * MINUTE),
+            ..RecyclePolicy::UNLIMITED
+        };
+        assert!(check_limits(&policy, &metrics(4 * MINUTE, Duration::ZERO, 0), 0.0).is_ok());
+        assert!(matches!(
+            check_limits(&policy, &metrics(6 * MINUTE, Duration::ZERO, 0), 0.0),
+            Err(Rejection::LifetimeExceeded)
+        ));
+    }
+
+    #[test]
+    fn rejects_connections_past_max_since_checkout() {
+        let policy = RecyclePolicy {
+            max_since_checkout: Some(MINUTE),
+            ..RecyclePolicy::UNLIMITED
+        };
+        let recently_used = metrics(10 * MINUTE, Duration::from_secs(10), 5);
+        assert!(check_limits(&policy, &recently_used, 0.0).is_ok());
+        assert!(matches!(
+            check_limits(&policy, &metrics(10 * MINUTE, 2 * MINUTE, 5), 0.0),
+            Err(Rejection::SinceCheckoutExceeded)
+        ));
+    }
+
+    #[test]
+    fn rejects_connections_past_max_uses() {
+        let policy = RecyclePolicy {
+            max_uses: Some(3),
+            ..RecyclePolicy::UNLIMITED
+        };
+        // Handed out on creation and once per recycle.
+        assert!(check_limits(&policy, &metrics(MINUTE, Duration::ZERO, 1), 0.0).is_ok());
+        assert!(matches!(
+            check_limits(&policy, &metrics(MINUTE, Duration::ZERO, 2), 0.0),
+            Err(Rejection::UsesExceeded)
+        ));
+    }
+
+    #[test]
+    fn jitter_lowers_limits() {
+        let policy = RecyclePolicy {
+            max_lifetime: Some(10 * MINUTE),
+            max_uses: Some(10),
+            jitter: 0.5,
+            ..RecyclePolicy::UNLIMITED
+        };
+        let old = metrics(6 * MINUTE, Duration::ZERO, 0);
+        assert!(check_limits(&policy, &old, 0.0).is_ok());
+        assert!(check_limits(&policy, &old, 0.5).is_ok());
+        assert!(matches!(
+            check_limits(&policy, &old, 1.0),
+            Err(Rejection::LifetimeExceeded)
+        ));
+
+        let used = metrics(MINUTE, Duration::ZERO, 4);
+        assert!(check_limits(&policy, &used, 0.0).is_ok());
+        assert!(matches!(
+            check_limits(&policy, &used, 1.0),
+            Err(Rejection::UsesExceeded)
+        ));
+    }
+
+    #[test]
+    fn invalid_jitter_is_ignored() {
+        for jitter in [f64::NAN, -1.0] {
+            let policy = RecyclePolicy {
+                max_lifetime: Some(10 * MINUTE),
+                jitter,
+                ..RecyclePolicy::UNLIMITED
+            };
+            let metrics = metrics(6 * MINUTE, Duration::ZERO, 0);
+            assert!(check_limits(&policy, &metrics, 1.0).is_ok(), "{jitter}");
+        }
+    }
+}
@@ -0,0 +1,449 @@
+//! In-process AMQP 0-9-1 server for tests.
+//!
+//! A [`TestServer`] listens on a loopback port and speaks enough of the
+//! protocol for real amqprs connections, so a [`Pool`](crate::Pool) and the
+//! code using it can be tested without a running broker. It supports the
+//! handshake, channels, declaring, binding and deleting exchanges and
+//! queues, publishing with and without publisher confirms, `basic.get`,
+//! consumers with prefetch, acknowledgements, rejections and closing
+//! connections from the server side.
+//!
+//! Everything is kept in memory, all virtual hosts share the same exchanges
+//! and queues and `headers` exchanges route every message to all their
+//! bindings. Transactions aren't supported.
+//!
+//! # Example
+//!
+//! ```rs
+//! use deadpool_amqprs::{testing::TestServer, Config};
+//!
+//! let server = TestServer::start().await.unwrap();
+//! let pool = Config::from_url(&server.url()).unwrap().create_pool();
+//!
+//! let conn = pool.get().await.unwrap();
+//! assert_eq!(server.connection_count(), 1);
+//!
+//! // Simulate a broker shutdown.
+//! server.close_connections(320, "CONNECTION_FORCED - broker forced connection closure");
+//! ```
+//!
+//! With the `oauth2` feature enabled a [`TokenServer`] stands in for the
+//! token endpoint of an OAuth 2.0 authorization server.
+
+mod broker;
+mod codec;
+#[cfg(feature = "oauth2")]
+mod token;
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    sync::mpsc,
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::config::{encode, ConnectionConfig};
+
+#[cfg(feature = "oauth2")]
+pub use self::token::{TokenServer, TokenServerConfig};
+
+use self::{
+    broker::{Broker, Flow},
+    codec::{Decoder, Method, Value},
+};
+
+/// Largest channel number the server proposes.
+const CHANNEL_MAX: u16 = 2047;
+
+/// Heartbeat timeout in seconds the server proposes.
+const HEARTBEAT: u16 = 60;
+
+/// Bytes queued for sending on a connection.
+enum Outgoing {
+    /// One or more encoded frames.
+    Frame(Vec<u8>),
+    /// Send heartbeats whenever nothing was sent for this long.
+    Heartbeat(Duration),
+}
+
+type Outbox = mpsc::UnboundedSender<Outgoing>;
+
+/// Configuration of a [`TestServer`].
+#[derive(Clone, Debug)]
+pub struct TestServerConfig {
+    /// User name clients must authenticate with.
+    ///
+    /// Default: `guest`
+    pub username: String,
+    /// Password clients must authenticate with.
+    ///
+    /// Default: `guest`
+    pub password: String,
+    /// Virtual hosts clients may open.
+    ///
+    /// Default: `["/"]`
+    pub vhosts: Vec<String>,
+}
+
+impl Default for TestServerConfig {
+    fn default() -> Self {
+        Self {
+            username: "guest".to_owned(),
+            password: "guest".to_owned(),
+            vhosts: vec!["/".to_owned()],
+        }
+    }
+}
+
+struct Shared {
+    config: TestServerConfig,
+    broker: Mutex<Broker>,
+}
+
+impl Shared {
+    fn broker(&self) -> MutexGuard<'_, Broker> {
+        self.broker.lock().unwrap()
+    }
+}
+
+/// AMQP 0-9-1 server listening on a loopback port.
+///
+/// The server and all its connections are shut down when it is dropped.
+pub struct TestServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    task: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Starts a server with the default [`TestServerConfig`] on a free port.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if binding the port failed.
+    pub async fn start() -> io::Result<Self> {
+        Self::start_with_config(TestServerConfig::default()).await
+    }
+
+    /// Starts a server with the given configuration on a free port.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if binding the port failed.
+    pub async fn start_with_config(config: TestServerConfig) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            config,
+            broker: Mutex::new(Broker::new()),
+        });
+        let task = tokio::spawn(accept(listener, shared.clone()));
+        Ok(Self { addr, shared, task })
+    }
+
+    /// Returns the address the server listens on.
+    #[must_use]
+    pub const fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns an AMQP URI with the credentials and first virtual host of
+    /// the server.
+    #[must_use]
+    pub fn url(&self) -> String {
+        let config = &self.shared.config;
+        let vhost = config.vhosts.first().map_or("/", String::as_str);
+        format!(
+            "amqp://{}:{}@{}/{}",
+            encode(&config.username),
+            encode(&config.password),
+            self.addr,
+            encode(vhost)
+        )
+    }
+
+    /// Returns a [`ConnectionConfig`] with the credentials and first virtual
+    /// host of the server.
+    #[must_use]
+    pub fn connection_config(&self) -> ConnectionConfig {
+        let config = &self.shared.config;
+        ConnectionConfig {
+            host: self.addr.ip().to_string(),
+            port: self.addr.port(),
+            vhost: config
+                .vhosts
+                .first()
+                .cloned()
+                .unwrap_or_else(|| "/".to_owned()),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            ..ConnectionConfig::default()
+        }
+    }
+
+    /// Returns the number of open connections.
+    #[must_use]
+    pub fn connection_count(&self) -> usize {
+        self.shared.broker().connection_count()
+    }
+
+    /// Returns whether an exchange exists.
+    #[must_use]
+    pub fn has_exchange(&self, exchange: &str) -> bool {
+        self.shared.broker().has_exchange(exchange)
+    }
+
+    /// Returns the number of ready messages in a queue, or [`None`] if it
+    /// doesn't exist.
+    #[must_use]
+    pub fn queue_len(&self, queue: &str) -> Option<usize> {
+        self.shared.broker().queue_len(queue)
+    }
+
+    /// Returns the number of consumers of a queue, or [`None`] if it doesn't
+    /// exist.
+    #[must_use]
+    pub fn consumer_count(&self, queue: &str) -> Option<usize> {
+        self.shared.broker().consumer_count(queue)
+    }
+
+    /// Publishes a message without properties, as if a client published it.
+    pub fn publish(&self, exchange: &str, routing_key: &str, body: impl Into<Vec<u8>>) {
+        self.shared
+            .broker()
+            .inject(exchange, routing_key, body.into());
+    }
+
+    /// Closes all connections with `connection.close`, like a broker shutting
+    /// down or an operator force-closing them.
+    pub fn close_connections(&self, reply_code: u16, reply_text: &str) {
+        self.shared.broker().close_all(reply_code, reply_text);
+    }
+
+    /// Sends `connection.blocked` with `reason` to all connections.
+    pub fn block_connections(&self, reason: &str) {
+        self.shared.broker().set_blocked(Some(reason));
+    }
+
+    /// Sends `connection.unblocked` to all connections.
+    pub fn unblock_connections(&self) {
+        self.shared.broker().set_blocked(None);
+    }
+}
+
+impl std::fmt::Debug for TestServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TestServer")
+            .field("addr", &self.addr)
+            .field("config", &self.shared.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Accepts connections until the server is dropped, which aborts the task
+/// and with it all connections in `sessions`.
+async fn accept(listener: TcpListener, shared: Arc<Shared>) {
+    let mut sessions = JoinSet::new();
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                sessions.spawn(serve(stream, shared.clone()));
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    }
+}
+
+/// Parameters negotiated during the handshake.
+struct Tuned {
+    frame_max: u32,
+    heartbeat: u16,
+}
+
+async fn serve(stream: TcpStream, shared: Arc<Shared>) {
+    let _ = stream.set_nodelay(true);
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let (outbox, outgoing) = mpsc::unbounded_channel();
+    let writer = tokio::spawn(write_frames(writer, outgoing));
+
+    if let Ok(Some(tuned)) = handshake(&mut reader, &outbox, &shared.config).await {
+        let id = shared.broker().connect(outbox.clone(), tuned.frame_max);
+        reply(&outbox, Method::new(10, 41).shortstr(""));
+        if tuned.heartbeat > 0 {
+            let interval = Duration::from_secs(u64::from(tuned.heartbeat)) / 2;
+            let _ = outbox.send(Outgoing::Heartbeat(interval));
+        }
+        while let Ok(frame) = codec::read_frame(&mut reader).await {
+            if shared.broker().handle(id, &frame) == Flow::Stop {
+                break;
+            }
+        }
+        shared.broker().disconnect(id);
+    }
+
+    // The writer stops once the frames queued so far are sent.
+    drop(outbox);
+    let _ = writer.await;
+}
+
+/// Writes queued frames to the client, sending heartbeats while idle.
+async fn write_frames(mut writer: OwnedWriteHalf, mut outgoing: mpsc::UnboundedReceiver<Outgoing>) {
+    let mut heartbeat = None;
+    loop {
+        let next = match heartbeat {
+            Some(interval) => match tokio::time::timeout(interval, outgoing.recv()).await {
+                Ok(next) => next,
+                Err(_) => Some(Outgoing::Frame(codec::heartbeat())),
+            },
+            None => outgoing.recv().await,
+        };
+        match next {
+            Some(Outgoing::Frame(frames)) => {
+                if writer.write_all(&frames).await.is_err() {
+                    break;
+                }
+            }
+            Some(Outgoing::Heartbeat(interval)) => heartbeat = Some(interval),
+            None => break,
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+fn reply(outbox: &Outbox, method: Method) {
+    let _ = outbox.send(Outgoing::Frame(method.frame(0)));
+}
+
+/// Negotiates a connection up to `connection.open`.
+///
+/// Returns [`None`] if the client was refused.
+async fn handshake<R>(
+    reader: &mut R,
+    outbox: &Outbox,
+    config: &TestServerConfig,
+) -> io::Result<Option<Tuned>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0; 8];
+    reader.read_exact(&mut header).await?;
+    if &header != codec::PROTOCOL_HEADER {
+        let _ = outbox.send(Outgoing::Frame(codec::PROTOCOL_HEADER.to_vec()));
+        return Ok(None);
+    }
+
+    reply(
+        outbox,
+        Method::new(10, 10)
+            .octet(0)
+            .octet(9)
+            .table(&server_properties())
+            .longstr(b"PLAIN")
+            .longstr(b"en_US"),
+    );
+    let start_ok = read_method(reader, (10, 11)).await?;
+    let mut args = Decoder::new(&start_ok);
+    args.table()?;
+    let mechanism = args.shortstr()?;
+    let response = args.longstr()?;
+    let expected = format!("\0{}\0{}", config.username, config.password);
+    if mechanism != "PLAIN" || response != expected.as_bytes() {
+        let text = format!(
+            "ACCESS_REFUSED - Login was refused using authentication mechanism {mechanism}"
+        );
+        reply(outbox, close(403, &text, (0, 0)));
+        return Ok(None);
+    }
+
+    reply(
+        outbox,
+        Method::new(10, 30)
+            .short(CHANNEL_MAX)
+            .long(codec::FRAME_MAX)
+            .short(HEARTBEAT),
+    );
+    let tune_ok = read_method(reader, (10, 31)).await?;
+    let mut args = Decoder::new(&tune_ok);
+    args.short()?;
+    let frame_max = args.long()?;
+    let heartbeat = args.short()?;
+
+    let open = read_method(reader, (10, 40)).await?;
+    let vhost = Decoder::new(&open).shortstr()?;
+    if !config.vhosts.contains(&vhost) {
+        let text = format!("NOT_ALLOWED - vhost {vhost} not found");
+        reply(outbox, close(530, &text, (10, 40)));
+        return Ok(None);
+    }
+    Ok(Some(Tuned {
+        frame_max,
+        heartbeat,
+    }))
+}
+
+/// Reads the arguments of the next method, which must be `expected`.
+async fn read_method<R>(reader: &mut R, expected: (u16, u16)) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let frame = codec::read_frame(reader).await?;
+        if frame.kind == codec::FRAME_HEARTBEAT {
+            continue;
+        }
+        if frame.channel == 0 && frame.method_id() == Some(expected) {
+            return Ok(frame.arguments().to_vec());
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected frame during handshake",
+        ));
+    }
+}
+
+fn close(reply_code: u16, reply_text: &str, (class, method): (u16, u16)) -> Method {
+    Method::new(10, 50)
+        .short(reply_code)
+        .shortstr(reply_text)
+        .short(class)
+        .short(method)
+}
+
+fn server_properties() -> Vec<(&'static str, Value<'static>)> {
+    let capabilities = [
+        "authentication_failure_close",
+        "basic.nack",
+        "connection.blocked",
+        "consumer_cancel_notify",
+        "exchange_exchange_bindings",
+        "per_consumer_qos",
+        "publisher_confirms",
+    ];
+    vec![
+        ("product", Value::Str("deadpool-amqprs test server")),
+        ("version", Value::Str(env!("CARGO_PKG_VERSION"))),
+        (
+            "capabilities",
+            Value::Table(
+                capabilities
+                    .into_iter()
+                    .map(|capability| (capability, Value::Bool(true)))
+                    .collect(),
+            ),
+        ),
+    ]
+}
@@ -0,0 +1,1230 @@
+//! In-memory state of the test server shared by all its connections.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    io,
+};
+
+use super::{
+    codec::{self, bit, Decoder, Frame, Method},
+    Outbox, Outgoing,
+};
+
+/// Whether a connection keeps being served after a frame.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum Flow {
+    Continue,
+    Stop,
+}
+
+/// Error closing a channel or the whole connection.
+struct Exception {
+    code: u16,
+    text: String,
+    connection: bool,
+}
+
+impl Exception {
+    fn channel(code: u16, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            text: text.into(),
+            connection: false,
+        }
+    }
+
+    fn connection(code: u16, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            text: text.into(),
+            connection: true,
+        }
+    }
+
+    fn not_found(kind: &str, name: &str) -> Self {
+        Self::channel(404, format!("NOT_FOUND - no {kind} '{name}'"))
+    }
+}
+
+impl From<io::Error> for Exception {
+    fn from(_: io::Error) -> Self {
+        Self::connection(502, "SYNTAX_ERROR - malformed frame")
+    }
+}
+
+#[derive(Clone)]
+struct Message {
+    exchange: String,
+    routing_key: String,
+    /// Property flags and property list as sent by the publisher.
+    properties: Vec<u8>,
+    body: Vec<u8>,
+    redelivered: bool,
+}
+
+/// Message published on a channel whose content is still being received.
+struct Content {
+    exchange: String,
+    routing_key: String,
+    mandatory: bool,
+    body_size: Option<u64>,
+    properties: Vec<u8>,
+    body: Vec<u8>,
+}
+
+/// Message delivered but not acknowledged yet.
+struct Delivery {
+    queue: String,
+    message: Message,
+}
+
+#[derive(Default)]
+struct Channel {
+    /// Set once the server sent `channel.close`.
+    closing: bool,
+    /// Sequence number of the last published message in confirm mode.
+    confirms: Option<u64>,
+    prefetch: u16,
+    /// Last delivery tag assigned.
+    delivery_tag: u64,
+    unacked: BTreeMap<u64, Delivery>,
+    content: Option<Content>,
+}
+
+struct Peer {
+    outbox: Outbox,
+    frame_max: u32,
+    /// Set once the server sent `connection.close`.
+    closing: bool,
+    channels: HashMap<u16, Channel>,
+}
+
+struct Consumer {
+    peer: u64,
+    channel: u16,
+    tag: String,
+    no_ack: bool,
+}
+
+#[derive(Default)]
+struct Queue {
+    /// Connection an exclusive queue belongs to.
+    owner: Option<u64>,
+    auto_delete: bool,
+    messages: VecDeque<Message>,
+    consumers: Vec<Consumer>,
+    next_consumer: usize,
+}
+
+#[derive(PartialEq)]
+enum Destination {
+    Queue(String),
+    Exchange(String),
+}
+
+#[derive(PartialEq)]
+struct Binding {
+    source: String,
+    destination: Destination,
+    routing_key: String,
+}
+
+/// Exchanges, queues and connections of the test server.
+pub(super) struct Broker {
+    /// Last id assigned to a connection, generated queue or consumer tag.
+    last_id: u64,
+    peers: HashMap<u64, Peer>,
+    /// Types of the exchanges by name.
+    exchanges: HashMap<String, String>,
+    queues: HashMap<String, Queue>,
+    bindings: Vec<Binding>,
+}
+
+impl Broker {
+    pub(super) fn new() -> Self {
+        let exchanges = [
+            ("", "direct"),
+            ("amq.direct", "direct"),
+            ("amq.fanout", "fanout"),
+            ("amq.headers", "headers"),
+            ("amq.match", "headers"),
+            ("amq.topic", "topic"),
+        ];
+        Self {
+            last_id: 0,
+            peers: HashMap::new(),
+            exchanges: exchanges
+                .into_iter()
+                .map(|(name, kind)| (name.to_owned(), kind.to_owned()))
+                .collect(),
+            queues: HashMap::new(),
+            bindings: Vec::new(),
+        }
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    /// Registers a connection which completed the handshake.
+    pub(super) fn connect(&mut self, outbox: Outbox, frame_max: u32) -> u64 {
+        let id = self.next_id();
+        let peer = Peer {
+            outbox,
+            frame_max,
+            closing: false,
+            channels: HashMap::new(),
+        };
+        self.peers.insert(id, peer);
+        id
+    }
+
+    /// Forgets a connection, requeueing its unacknowledged messages and
+    /// deleting its exclusive queues.
+    pub(super) fn disconnect(&mut self, id: u64) {
+        let channels: Vec<u16> = self
+            .peers
+            .get(&id)
+            .map(|peer| peer.channels.keys().copied().collect())
+            .unwrap_or_default();
+        for channel in channels {
+            self.release_channel(id, channel);
+        }
+        self.peers.remove(&id);
+        let exclusive: Vec<String> = self
+            .queues
+            .iter()
+            .filter(|(_, queue)| queue.owner == Some(id))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in exclusive {
+            self.delete_queue(&name);
+        }
+    }
+
+    pub(super) fn connection_count(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub(super) fn has_exchange(&self, name: &str) -> bool {
+        self.exchanges.contains_key(name)
+    }
+
+    pub(super) fn queue_len(&self, name: &str) -> Option<usize> {
+        self.queues.get(name).map(|queue| queue.messages.len())
+    }
+
+    pub(super) fn consumer_count(&self, name: &str) -> Option<usize> {
+        self.queues.get(name).map(|queue| queue.consumers.len())
+    }
+
+    /// Routes a message without properties as if a client published it.
+    pub(super) fn inject(&mut self, exchange: &str, routing_key: &str, body: Vec<u8>) {
+        let message = Message {
+            exchange: exchange.to_owned(),
+            routing_key: routing_key.to_owned(),
+            properties: vec![0, 0],
+            body,
+            redelivered: false,
+        };
+        let queues = self.route(exchange, routing_key);
+        self.enqueue(&queues, &message);
+    }
+
+    /// Sends `connection.close` to every connection.
+    pub(super) fn close_all(&mut self, reply_code: u16, reply_text: &str) {
+        for peer in self.peers.values_mut().filter(|peer| !peer.closing) {
+            peer.closing = true;
+            let close = Method::new(10, 50)
+                .short(reply_code)
+                .shortstr(reply_text)
+                .short(0)
+                .short(0);
+            let _ = peer.outbox.send(Outgoing::Frame(close.frame(0)));
+        }
+    }
+
+    /// Sends `connection.blocked` with `reason`, or `connection.unblocked`,
+    /// to every connection.
+    pub(super) fn set_blocked(&self, reason: Option<&str>) {
+        let method = match reason {
+            Some(reason) => Method::new(10, 60).shortstr(reason),
+            None => Method::new(10, 61),
+        }
+        .frame(0);
+        for peer in self.peers.values() {
+            let _ = peer.outbox.send(Outgoing::Frame(method.clone()));
+        }
+    }
+
+    /// Handles a frame received on connection `id`.
+    pub(super) fn handle(&mut self, id: u64, frame: &Frame) -> Flow {
+        let Some(peer) = self.peers.get(&id) else {
+            return Flow::Stop;
+        };
+        let method_id = frame.method_id();
+        if peer.closing {
+            // Only the handshake closing the connection matters now.
+            return match method_id {
+                Some((10, 50)) => {
+                    self.send(id, 0, Method::new(10, 51));
+                    Flow::Stop
+                }
+                Some((10, 51)) => Flow::Stop,
+                _ => Flow::Continue,
+            };
+        }
+        let (class, method) = method_id.unwrap_or_default();
+        let handled = match frame.kind {
+            codec::FRAME_HEARTBEAT => Ok(Flow::Continue),
+            codec::FRAME_METHOD if frame.channel == 0 => self.connection_method(id, class, method),
+            codec::FRAME_METHOD => self
+                .channel_method(id, frame.channel, class, method, frame.arguments())
+                .map(|()| Flow::Continue),
+            codec::FRAME_HEADER | codec::FRAME_BODY => self
+                .content(id, frame.channel, frame.kind, &frame.payload)
+                .map(|()| Flow::Continue),
+            _ => Err(Exception::connection(
+                501,
+                "FRAME_ERROR - unknown frame type",
+            )),
+        };
+        handled.unwrap_or_else(|exception| {
+            self.raise(id, frame.channel, class, method, exception);
+            Flow::Continue
+        })
+    }
+
+    fn send(&self, id: u64, channel: u16, method: Method) {
+        if let Some(peer) = self.peers.get(&id) {
+            let _ = peer.outbox.send(Outgoing::Frame(method.frame(channel)));
+        }
+    }
+
+    fn send_content(&self, id: u64, channel: u16, method: Method, message: &Message) {
+        if let Some(peer) = self.peers.get(&id) {
+            let mut frames = method.frame(channel);
+            frames.extend(codec::content(
+                channel,
+                peer.frame_max,
+                &message.properties,
+                &message.body,
+            ));
+            let _ = peer.outbox.send(Outgoing::Frame(frames));
+        }
+    }
+
+    fn channel_mut(&mut self, id: u64, channel: u16) -> Option<&mut Channel> {
+        self.peers.get_mut(&id)?.channels.get_mut(&channel)
+    }
+
+    /// Closes the channel or connection the failed method was received on.
+    fn raise(&mut self, id: u64, channel: u16, class: u16, method: u16, exception: Exception) {
+        let close = |class_id, method_id| {
+            Method::new(class_id, method_id)
+                .short(exception.code)
+                .shortstr(&exception.text)
+                .short(class)
+                .short(method)
+        };
+        if exception.connection || channel == 0 {
+            if let Some(peer) = self.peers.get_mut(&id) {
+                peer.closing = true;
+            }
+            self.send(id, 0, close(10, 50));
+        } else {
+            self.release_channel(id, channel);
+            if let Some(state) = self.channel_mut(id, channel) {
+                state.closing = true;
+                state.content = None;
+            }
+            self.send(id, channel, close(20, 40));
+        }
+    }
+
+    fn connection_method(&mut self, id: u64, class: u16, method: u16) -> Result<Flow, Exception> {
+        match (class, method) {
+            (10, 50) => {
+                self.send(id, 0, Method::new(10, 51));
+                Ok(Flow::Stop)
+            }
+            (10, 70) => {
+                self.send(id, 0, Method::new(10, 71));
+                Ok(Flow::Continue)
+            }
+            _ => Err(Exception::connection(
+                503,
+                format!("COMMAND_INVALID - unexpected method {class}.{method} on channel 0"),
+            )),
+        }
+    }
+
+    fn channel_method(
+        &mut self,
+        id: u64,
+        channel: u16,
+        class: u16,
+        method: u16,
+        args: &[u8],
+    ) -> Result<(), Exception> {
+        let state = self
+            .peers
+            .get(&id)
+            .and_then(|peer| peer.channels.get(&channel))
+            .map(|state| (state.closing, state.content.is_some()));
+        match (state, class, method) {
+            (None, 20, 10) => {
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    peer.channels.insert(channel, Channel::default());
+                }
+                self.send(id, channel, Method::new(20, 11).longstr(b""));
+                return Ok(());
+            }
+            (None, ..) => {
+                return Err(Exception::connection(
+                    504,
+                    format!("CHANNEL_ERROR - expected 'channel.open' on channel {channel}"),
+                ))
+            }
+            (Some(_), 20, 10) => {
+                return Err(Exception::connection(
+                    504,
+                    "CHANNEL_ERROR - second 'channel.open' seen",
+                ))
+            }
+            (Some((true, _)), 20, 40 | 41) => {
+                self.remove_channel(id, channel);
+                if method == 40 {
+                    self.send(id, channel, Method::new(20, 41));
+                }
+                return Ok(());
+            }
+            // Everything else is discarded until the close is confirmed.
+            (Some((true, _)), ..) => return Ok(()),
+            (Some((_, true)), ..) => {
+                return Err(Exception::connection(
+                    505,
+                    "UNEXPECTED_FRAME - expected content header",
+                ))
+            }
+            (Some(_), ..) => {}
+        }
+
+        let mut args = Decoder::new(args);
+        match (class, method) {
+            (20, 20) => {
+                let active = bit(args.octet()?, 0);
+                self.send(id, channel, Method::new(20, 21).bits(&[active]));
+            }
+            (20, 40) => {
+                self.release_channel(id, channel);
+                self.remove_channel(id, channel);
+                self.send(id, channel, Method::new(20, 41));
+            }
+            (40, 10) => self.exchange_declare(id, channel, &mut args)?,
+            (40, 20) => self.exchange_delete(id, channel, &mut args)?,
+            (40, 30) => self.exchange_bind(id, channel, &mut args, true)?,
+            (40, 40) => self.exchange_bind(id, channel, &mut args, false)?,
+            (50, 10) => self.queue_declare(id, channel, &mut args)?,
+            (50, 20) => self.queue_bind(id, channel, &mut args, true)?,
+            (50, 30) => self.queue_purge(id, channel, &mut args)?,
+            (50, 40) => self.queue_delete(id, channel, &mut args)?,
+            (50, 50) => self.queue_bind(id, channel, &mut args, false)?,
+            (60, 10) => {
+                args.long()?;
+                let prefetch = args.short()?;
+                if let Some(state) = self.channel_mut(id, channel) {
+                    state.prefetch = prefetch;
+                }
+                self.send(id, channel, Method::new(60, 11));
+                self.dispatch_all();
+            }
+            (60, 20) => self.basic_consume(id, channel, &mut args)?,
+            (60, 30) => self.basic_cancel(id, channel, &mut args)?,
+            (60, 40) => self.basic_publish(id, channel, &mut args)?,
+            (60, 70) => self.basic_get(id, channel, &mut args)?,
+            (60, 80) => {
+                let tag = args.longlong()?;
+                let multiple = bit(args.octet()?, 0);
+                self.settle(id, channel, tag, multiple, false)?;
+            }
+            (60, 90) => {
+                let tag = args.longlong()?;
+                let requeue = bit(args.octet()?, 0);
+                self.settle(id, channel, tag, false, requeue)?;
+            }
+            (60, 100 | 110) => {
+                // Unacknowledged messages are always requeued.
+                let unacked = self
+                    .channel_mut(id, channel)
+                    .map(|state| std::mem::take(&mut state.unacked))
+                    .unwrap_or_default();
+                self.requeue(unacked.into_values());
+                if method == 110 {
+                    self.send(id, channel, Method::new(60, 111));
+                }
+                self.dispatch_all();
+            }
+            (60, 120) => {
+                let tag = args.longlong()?;
+                let flags = args.octet()?;
+                self.settle(id, channel, tag, bit(flags, 0), bit(flags, 1))?;
+            }
+            (85, 10) => {
+                let no_wait = bit(args.octet()?, 0);
+                if let Some(state) = self.channel_mut(id, channel) {
+                    if state.confirms.is_none() {
+                        state.confirms = Some(0);
+                    }
+                }
+                if !no_wait {
+                    self.send(id, channel, Method::new(85, 11));
+                }
+            }
+            _ => {
+                return Err(Exception::connection(
+                    540,
+                    format!("NOT_IMPLEMENTED - method {class}.{method}"),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_channel(&mut self, id: u64, channel: u16) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.channels.remove(&channel);
+        }
+    }
+
+    /// Cancels the consumers of a channel and requeues its unacknowledged
+    /// messages.
+    fn release_channel(&mut self, id: u64, channel: u16) {
+        let mut abandoned = Vec::new();
+        for (name, queue) in &mut self.queues {
+            let consumers = queue.consumers.len();
+            queue
+                .consumers
+                .retain(|consumer| consumer.peer != id || consumer.channel != channel);
+            if queue.auto_delete && consumers > 0 && queue.consumers.is_empty() {
+                abandoned.push(name.clone());
+            }
+        }
+        let unacked = self
+            .channel_mut(id, channel)
+            .map(|state| std::mem::take(&mut state.unacked))
+            .unwrap_or_default();
+        self.requeue(unacked.into_values());
+        for name in abandoned {
+            self.delete_queue(&name);
+        }
+        self.dispatch_all();
+    }
+
+    fn exchange_declare(
+        &mut self,
+        id: u64,
+        channel: u16,
+        args: &mut Decoder<'_>,
+    ) -> Result<(), Exception> {
+        args.short()?;
+        let name = args.shortstr()?;
+        let kind = args.shortstr()?;
+        let flags = args.octet()?;
+        args.table()?;
+        let (passive, no_wait) = (bit(flags, 0), bit(flags, 4));
+        match self.exchanges.get(&name).cloned() {
+            Some(existing) if !passive && existing != kind => {
+                return Err(Exception::channel(
+                    406,
+                    format!(
+                        "PRECONDITION_FAILED - inequivalent arg 'type' for exchange '{name}': \
+                         received '{kind}' but current is '{existing}'"
+                    ),
+                ))
+            }
+            Some(_) => {}
+            None if passive => return Err(Exception::not_found("exchange", &name)),
+            None => {
+                self.exchanges.insert(name, kind);
+            }
+        }
+        if !no_wait {
+            self.send(id, channel, Method::new(40, 11));
+        }
+        Ok(())
+    }
+
+    fn exchange_delete(
+        &mut self,
+        id: u64,
+        channel: u16,
+        args: &mut Decoder<'_>,
+    ) -> Result<(), Exception> {
+        args.short()?;
+        let name = args.shortstr()?;
+        let no_wait = bit(args.octet()?, 1);
+        if name.is_empty() || name.starts_with("amq.") {
+            return Err(Exception::channel(
+                403,
+                format!("ACCESS_REFUSED - operation not permitted on exchange '{name}'"),
+            ));
+        }
+        self.exchanges.remove(&name);
+        let exchange = Destination::Exchange(name.clone());
+        self.bindings
+            .retain(|binding| binding.source != name && binding.destination != exchange);
+        if !no_wait {
+            self.send(id, channel, Method::new(40, 21));
+        }
+        Ok(())
+    }
+
+    fn exchange_bind(
+        &mut self,
+        id: u64,
+        channel: u16,
+        args: &mut Decoder<'_>,
+        bind: bool,
+    ) -> Result<(), Exception> {
+        args.short()?;
+        let destination = args.shortstr()?;
+        let source = args.shortstr()?;
+        let routing_key = args.shortstr()?;
+        let no_wait = bit(args.octet()?, 0);
+        args.table()?;
+        for name in [&destination, &source] {
+            if name.is_empty() {
+                return Err(Exception::channel(
+                    403,
+                    "ACCESS_REFUSED - operation not permitted on the default exchange",
+                ));
+            }
+            if !self.exchanges.contains_key(name) {
+                return Err(Exception::not_found("exchange", name));
+            }
+        }
+        let binding = Binding {
+            source,
+            destination: Destination::Exchange(destination),
+            routing_key,
+        };
+        self.bind(binding, bind);
+        if !no_wait {
+            let reply = if bind {
+                Method::new(40, 31)
+            } else {
+                Method::new(40, 51)
+            };
+            self.send(id, channel, reply);
+        }
+        Ok(())
+    }
+
+    fn queue_declare(
+        &mut self,
+        id: u64,
+        channel: u16,
+        args: &mut Decoder<'_>,
+    ) -> Result<(), Exception> {
+        args.short()?;
+        let name = args.shortstr()?;
+        let flags = args.octet()?;
+        args.table()?;
+        let (passive, exclusive, auto_delete, no_wait) =
+            (bit(flags, 0), bit(flags, 2), bit(flags, 3), bit(flags, 4));
+        let name = if name.is_empty() {
+            format!("amq.gen-{}", self.next_id())
+        } else {
+            name
+        };
+        match self.queues.get(&name).map(|queue| queue.owner) {
+            Some(Some(owner)) if owner != id => return Err(locked(&name)),
+            Some(_) => {}
+            None if passive => return Err(Exception::not_found("queue", &name)),
+            None => {
+                let queue = Queue {
+                    owner: exclusive.then_some(id),
+                    auto_delete,
+                    ..Queue::default()
+                };
+                self.queues.insert(name.clone(), queue);
+            }
+        }
+        if !no_wait {
+            let queue = &self.queues[&name];
+            let reply = Method::new(50, 11)
+                .shortstr(&name)
+                .long(count(queue.messages.len()))
+                .long(count(queue.consumers.len()));
+            self.send(id, channel, reply);
+        }
+        Ok(())
+    }
+
+    fn queue_bind(
+        &mut self,
+        id: u64,
+        channel: u16,
+        args: &mut Decoder<'_>,
+        bind: bool,
+    ) -> Result<(), Exception> {
+        args.short()?;
+        let queue = args.shortstr()?;
+        let exchange = args.shortstr()?;
+        let routing_key = args.shortstr()?;
+        // `queue.unbind` has no `no-wait` flag.
+        let no_wait = bind && bit(args.octet()?, 0);
+        args.table()?;
+        if exchange.is_empty() {
+            return Err(Exception::channel(
+                403,
+                "ACCESS_REFUSED - operation not permitted on the default exchange",
+            ));
+        }
+        if !self.queues.contains_key(&queue) {
+            return Err(Exception::not_found("queue", &queue));
+        }
+        if !self.exchanges.contains_key(&exchange) {
+            return Err(Exception::not_found("exchange", &exchange));
+        }
+        let binding = Binding {
+            source: exchange,
+            destination: Destination::Queue(queue),
+            routing_key,
+        };
+        self.bind(binding, bind);
+        if !no_wait {
+            let reply = if bind {
+                Method::new(50, 21)
+            } else {
+                Method::new(50, 51)
+            };
+            self.send(id, channel, reply);
+        }
+        Ok(())
+    }
+
+    fn bind(&mut self, binding: Binding, bind: bool) {
+        if !bind {
+            self.bindings.retain(|existing| *existing != binding);
+        } else if !self.bindings.contains(&binding) {
+            self.bindings.push(binding);
+        }
+    }
+
+    fn queue_purge(
+        &mut self,
+        id: u64,
+        channel: u16,
+        args: &mut Decoder<'_>,
+    ) -> Result<(), Exception> {
+        args.short()?;
+        let name = args.shortstr()?;
+        let no_wait = bit(args.octet()?, 0);
+        let queue = self
+            .queues
+            .get_mut(&name)
+            .ok_or_else(|| Exception::not_found("queue", &name))?;
+        let purged = count(queue.messages.len());
+        queue.messages.clear();
+        if !no_wait {
+            self.send(id, channel, Method::new(50, 31).long(purged));
+        }
+        Ok(())
+    }
+
+    fn queue_delete(
+        &mut self,
+        id: u64,
+        channel: u16,
+        args: &mut Decoder<'_>,
+    ) -> Result<(), Exception> {
+        args.short()?;
+        let name = args.shortstr()?;
+        let flags = args.octet()?;
+        let (if_unused, if_empty, no_wait) = (bit(flags, 0), bit(flags, 1), bit(flags, 2));
+        if let Some(queue) = self.queues.get(&name) {
+            if queue.owner.is_some_and(|owner| owner != id) {
+                return Err(locked(&name));
+            }
+            if if_unused && !queue.consumers.is_empty() {
+                return Err(Exception::channel(
+                    406,
+                    format!("PRECONDITION_FAILED - queue '{name}' in use"),
+                ));
+            }
+            if if_empty && !queue.messages.is_empty() {
+                return Err(Exception::channel(
+                    406,
+                    format!("PRECONDITION_FAILED - queue '{name}' not empty"),
+                ));
+            }
+        }
+        let deleted = self.delete_queue(&name);
+        if !no_wait {
+            self.send(id, channel, Method::new(50, 41).long(count(deleted)));
+        }
+        Ok(())
+    }
+
+    /// Deletes a queue, cancelling its consumers, and returns the number of
+    /// messages it held.
+    fn delete_queue(&mut self, name: &str) -> usize {
+        let Some(queue) = self.queues.remove(name) else {
+            return 0;
+        };
+        let destination = Destination::Queue(name.to_owned());
+        self.bindings
+            .retain(|binding| binding.destination != destination);
+        for consumer in &queue.consumers {
+            let cancel = Method::new(60, 30).shortstr(&consumer.tag).bits(&[true]);
+            self.send(consumer.peer, consumer.channel, cancel);
+        }
+        queue.messages.len()
+    }
+
+    fn basic_consume(
+        &mut self,
+        id: u64,
+        channel: u16,
+        args: &mut Decoder<'_>,
+    ) -> Result<(), Exception> {
+        args.short()?;
+        let name = args.shortstr()?;
+        let tag = args.shortstr()?;
+        let flags = args.octet()?;
+        args.table()?;
+        let (no_ack, no_wait) = (bit(flags, 1), bit(flags, 3));
+        match self.queues.get(&name).map(|queue| queue.owner) {
+            None => return Err(Exception::not_found("queue", &name)),
+            Some(Some(owner)) if owner != id => return Err(locked(&name)),
+            Some(_) => {}
+        }
+        let tag = if tag.is_empty() {
+            format!("amq.ctag-{}", self.next_id())
+        } else {
+            tag
+        };
+        let reused = self
+            .queues
+            .values()
+            .flat_map(|queue| &queue.consumers)
+            .any(|consumer| {
+                consumer.peer == id && consumer.channel == channel && consumer.tag == tag
+            });
+        if reused {
+            return Err(Exception::connection(
+                530,
+                format!("NOT_ALLOWED - attempt to reuse consumer tag '{tag}'"),
+            ));
+        }
+        if !no_wait {
+            self.send(id, channel, Method::new(60, 21).shortstr(&tag));
+        }
+        if let Some(queue) = self.queues.get_mut(&name) {
+            queue.consumers.push(Consumer {
+                peer: id,
+                channel,
+                tag,
+                no_ack,
+            });
+        }
+        self.dispatch(&name);
+        Ok(())
+    }
+
+    fn basic_cancel(
+        &mut self,
+        id: u64,
+        channel: u16,
+        args: &mut Decoder<'_>,
+    ) -> Result<(), Exception> {
+        let tag = args.shortstr()?;
+        let no_wait = bit(args.octet()?, 0);
+        let mut abandoned = None;
+        for (name, queue) in &mut self.queues {
+            let consumers = queue.consumers.len();
+            queue.consumers.retain(|consumer| {
+                consumer.peer != id || consumer.channel != channel || consumer.tag != tag
+            });
+            if queue.auto_delete && consumers > 0 && queue.consumers.is_empty() {
+                abandoned = Some(name.clone());
+            }
+        }
+        if let Some(name) = abandoned {
+            self.delete_queue(&name);
+        }
+        if !no_wait {
+            self.send(id, channel, Method::new(60, 31).shortstr(&tag));
+        }
+        Ok(())
+    }
+
+    fn basic_publish(
+        &mut self,
+        id: u64,
+        channel: u16,
+        args: &mut Decoder<'_>,
+    ) -> Result<(), Exception> {
+        args.short()?;
+        let exchange = args.shortstr()?;
+        let routing_key = args.shortstr()?;
+        let mandatory = bit(args.octet()?, 0);
+        if !self.exchanges.contains_key(&exchange) {
+            return Err(Exception::not_found("exchange", &exchange));
+        }
+        if let Some(state) = self.channel_mut(id, channel) {
+            state.content = Some(Content {
+                exchange,
+                routing_key,
+                mandatory,
+                body_size: None,
+                properties: Vec::new(),
+                body: Vec::new(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Collects the content header and body frames of a published message.
+    fn content(
+        &mut self,
+        id: u64,
+        channel: u16,
+        kind: u8,
+        payload: &[u8],
+    ) -> Result<(), Exception> {
+        let Some(state) = self.channel_mut(id, channel) else {
+            return Err(Exception::connection(
+                504,
+                format!("CHANNEL_ERROR - content on closed channel {channel}"),
+            ));
+        };
+        if state.closing {
+            return Ok(());
+        }
+        let Some(content) = state.content.as_mut() else {
+            return Err(Exception::connection(
+                505,
+                "UNEXPECTED_FRAME - content without 'basic.publish'",
+            ));
+        };
+        match (kind, content.body_size) {
+            (codec::FRAME_HEADER, None) => {
+                let mut header = Decoder::new(payload);
+                header.short()?;
+                header.short()?;
+                content.body_size = Some(header.longlong()?);
+                content.properties = header.rest().to_vec();
+            }
+            (codec::FRAME_BODY, Some(_)) => content.body.extend_from_slice(payload),
+            _ => {
+                return Err(Exception::connection(
+                    505,
+                    "UNEXPECTED_FRAME - content frames out of order",
+                ))
+            }
+        }
+        let complete = content
+            .body_size
+            .is_some_and(|size| content.body.len() as u64 >= size);
+        if complete {
+            if let Some(content) = state.content.take() {
+                self.publish(id, channel, content);
+            }
+        }
+        Ok(())
+    }
+
+    fn publish(&mut self, id: u64, channel: u16, content: Content) {
+        let Content {
+            exchange,
+            routing_key,
+            mandatory,
+            properties,
+            body,
+            ..
+        } = content;
+        let message = Message {
+            exchange,
+            routing_key,
+            properties,
+            body,
+            redelivered: false,
+        };
+        let queues = self.route(&message.exchange, &message.routing_key);
+        if queues.is_empty() && mandatory {
+            let returned = Method::new(60, 50)
+                .short(312)
+                .shortstr("NO_ROUTE")
+                .shortstr(&message.exchange)
+                .shortstr(&message.routing_key);
+            self.send_content(id, channel, returned, &message);
+        }
+        let confirmed = self
+            .channel_mut(id, channel)
+            .and_then(|state| state.confirms.as_mut())
+            .map(|sequence| {
+                *sequence += 1;
+                *sequence
+            });
+        if let Some(tag) = confirmed {
+            self.send(
+                id,
+                channel,
+                Method::new(60, 80).longlong(tag).bits(&[false]),
+            );
+        }
+        self.enqueue(&queues, &message);
+    }
+
+    /// Returns the queues a message published to `exchange` is routed to.
+    fn route(&self, exchange: &str, routing_key: &str) -> Vec<String> {
+        let mut visited = Vec::new();
+        let mut queues = Vec::new();
+        self.route_into(exchange, routing_key, &mut visited, &mut queues);
+        queues
+    }
+
+    fn route_into<'a>(
+        &'a self,
+        exchange: &'a str,
+        routing_key: &str,
+        visited: &mut Vec<&'a str>,
+        queues: &mut Vec<String>,
+    ) {
+        if visited.contains(&exchange) {
+            return;
+        }
+        visited.push(exchange);
+        if exchange.is_empty() {
+            if self.queues.contains_key(routing_key) {
+                queues.push(routing_key.to_owned());
+            }
+            return;
+        }
+        let Some(kind) = self.exchanges.get(exchange) else {
+            return;
+        };
+        for binding in &self.bindings {
+            if binding.source != exchange || !matches(kind, &binding.routing_key, routing_key) {
+                continue;
+            }
+            match &binding.destination {
+                Destination::Queue(queue) => {
+                    if !queues.contains(queue) {
+                        queues.push(queue.clone());
+                    }
+                }
+                Destination::Exchange(destination) => {
+                    self.route_into(destination, routing_key, visited, queues);
+                }
+            }
+        }
+    }
+
+    fn enqueue(&mut self, queues: &[String], message: &Message) {
+        for name in queues {
+            if let Some(queue) = self.queues.get_mut(name) {
+                queue.messages.push_back(message.clone());
+            }
+        }
+        for name in queues {
+            self.dispatch(name);
+        }
+    }
+
+    /// Puts settled deliveries back at the front of their queues.
+    fn requeue(&mut self, deliveries: impl DoubleEndedIterator<Item = Delivery>) {
+        for Delivery { queue, mut message } in deliveries.rev() {
+            if let Some(queue) = self.queues.get_mut(&queue) {
+                message.redelivered = true;
+                queue.messages.push_front(message);
+            }
+        }
+    }
+
+    fn settle(
+        &mut self,
+        id: u64,
+        channel: u16,
+        tag: u64,
+        multiple: bool,
+        requeue: bool,
+    ) -> Result<(), Exception> {
+        let Some(state) = self.channel_mut(id, channel) else {
+            return Ok(());
+        };
+        let settled: Vec<Delivery> = if multiple {
+            let remaining = if tag == 0 {
+                BTreeMap::new()
+            } else {
+                state.unacked.split_off(&tag.saturating_add(1))
+            };
+            std::mem::replace(&mut state.unacked, remaining)
+                .into_values()
+                .collect()
+        } else {
+            state.unacked.remove(&tag).into_iter().collect()
+        };
+        if settled.is_empty() && !(multiple && tag == 0) {
+            return Err(Exception::channel(
+                406,
+                format!("PRECONDITION_FAILED - unknown delivery tag {tag}"),
+            ));
+        }
+        if requeue {
+            self.requeue(settled.into_iter());
+        }
+        self.dispatch_all();
+        Ok(())
+    }
+
+    fn dispatch_all(&mut self) {
+        let names: Vec<String> = self.queues.keys().cloned().collect();
+        for name in names {
+            self.dispatch(&name);
+        }
+    }
+
+    /// Delivers messages of a queue to its consumers in turn, as long as
+    /// their channels' prefetch limits allow.
+    fn dispatch(&mut self, name: &str) {
+        let Some(queue) = self.queues.get_mut(name) else {
+            return;
+        };
+        'messages: while !queue.messages.is_empty() {
+            let consumers = queue.consumers.len();
+            for offset in 0..consumers {
+                let index = (queue.next_consumer + offset) % consumers;
+                let consumer = &queue.consumers[index];
+                let Some(peer) = self.peers.get_mut(&consumer.peer) else {
+                    continue;
+                };
+                let Some(state) = peer.channels.get_mut(&consumer.channel) else {
+                    continue;
+                };
+                let saturated =
+                    state.prefetch != 0 && state.unacked.len() >= usize::from(state.prefetch);
+                if state.closing || (!consumer.no_ack && saturated) {
+                    continue;
+                }
+                let Some(message) = queue.messages.pop_front() else {
+                    break 'messages;
+                };
+                state.delivery_tag += 1;
+                let tag = state.delivery_tag;
+                let mut frames = Method::new(60, 60)
+                    .shortstr(&consumer.tag)
+                    .longlong(tag)
+                    .bits(&[message.redelivered])
+                    .shortstr(&message.exchange)
+                    .shortstr(&message.routing_key)
+                    .frame(consumer.channel);
+                frames.extend(codec::content(
+                    consumer.channel,
+                    peer.frame_max,
+                    &message.properties,
+                    &message.body,
+                ));
+                let _ = peer.outbox.send(Outgoing::Frame(frames));
+                if !consumer.no_ack {
+                    let delivery = Delivery {
+                        queue: name.to_owned(),
+                        message,
+                    };
+                    state.unacked.insert(tag, delivery);
+                }
+                queue.next_consumer = (index + 1) % consumers;
+                continue 'messages;
+            }
+            break;
+        }
+    }
+
+    fn basic_get(
+        &mut self,
+        id: u64,
+        channel: u16,
+        args: &mut Decoder<'_>,
+    ) -> Result<(), Exception> {
+        args.short()?;
+        let name = args.shortstr()?;
+        let no_ack = bit(args.octet()?, 0);
+        let queue = self
+            .queues
+            .get_mut(&name)
+            .ok_or_else(|| Exception::not_found("queue", &name))?;
+        let Some(message) = queue.messages.pop_front() else {
+            self.send(id, channel, Method::new(60, 72).shortstr(""));
+            return Ok(());
+        };
+        let remaining = count(queue.messages.len());
+        let Some(state) = self.channel_mut(id, channel) else {
+            return Ok(());
+        };
+        state.delivery_tag += 1;
+        let tag = state.delivery_tag;
+        let get_ok = Method::new(60, 71)
+            .longlong(tag)
+            .bits(&[message.redelivered])
+            .shortstr(&message.exchange)
+            .shortstr(&message.routing_key)
+            .long(remaining);
+        self.send_content(id, channel, get_ok, &message);
+        if !no_ack {
+            if let Some(state) = self.channel_mut(id, channel) {
+                let delivery = Delivery {
+                    queue: name,
+                    message,
+                };
+                state.unacked.insert(tag, delivery);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn locked(queue: &str) -> Exception {
+    Exception::channel(
+        405,
+        format!("RESOURCE_LOCKED - cannot obtain exclusive access to locked queue '{queue}'"),
+    )
+}
+
+/// Converts a length to a message or consumer count.
+fn count(len: usize) -> u32 {
+    u32::try_from(len).unwrap_or(u32::MAX)
+}
+
+/// Returns whether a binding with `pattern` of an exchange of type `kind`
+/// matches `routing_key`. `headers` exchanges match every message.
+fn matches(kind: &str, pattern: &str, routing_key: &str) -> bool {
+    match kind {
+        "fanout" | "headers" => true,
+        "topic" => {
+            let pattern: Vec<&str> = pattern.split('.').collect();
+            let words: Vec<&str> = routing_key.split('.').collect();
+            topic_matches(&pattern, &words)
+        }
+        _ => pattern == routing_key,
+    }
+}
+
+fn topic_matches(pattern: &[&str], words: &[&str]) -> bool {
+    match (pattern.split_first(), words.split_first()) {
+        (None, None) => true,
+        (Some((&"#", rest)), _) => {
+            topic_matches(rest, words)
+                || words
+                    .split_first()
+                    .is_some_and(|(_, words)| topic_matches(pattern, words))
+        }
+        (Some((expected, rest)), Some((word, words))) => {
+            (*expected == "*" || expected == word) && topic_matches(rest, words)
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,278 @@
+//! Encoding and decoding of AMQP 0-9-1 frames.
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Header a client sends before the first frame.
+pub(super) const PROTOCOL_HEADER: &[u8; 8] = b"AMQP\x00\x00\x09\x01";
+
+/// Largest frame the server accepts and proposes.
+pub(super) const FRAME_MAX: u32 = 131_072;
+
+pub(super) const FRAME_METHOD: u8 = 1;
+pub(super) const FRAME_HEADER: u8 = 2;
+pub(super) const FRAME_BODY: u8 = 3;
+pub(super) const FRAME_HEARTBEAT: u8 = 8;
+const FRAME_END: u8 = 0xCE;
+
+/// Size of the frame type, channel, size and frame end around a payload.
+const FRAME_OVERHEAD: u32 = 8;
+
+/// Class id of `basic`, the only class with content.
+const CLASS_BASIC: u16 = 60;
+
+/// Frame received from a client.
+pub(super) struct Frame {
+    pub(super) kind: u8,
+    pub(super) channel: u16,
+    pub(super) payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Returns the class and method id of a method frame.
+    pub(super) fn method_id(&self) -> Option<(u16, u16)> {
+        if self.kind != FRAME_METHOD {
+            return None;
+        }
+        let id = self.payload.get(..4)?;
+        Some((
+            u16::from_be_bytes([id[0], id[1]]),
+            u16::from_be_bytes([id[2], id[3]]),
+        ))
+    }
+
+    /// Returns the arguments of a method frame.
+    pub(super) fn arguments(&self) -> &[u8] {
+        self.payload.get(4..).unwrap_or_default()
+    }
+}
+
+/// Reads the next frame from `reader`.
+pub(super) async fn read_frame<R>(reader: &mut R) -> io::Result<Frame>
+where
+    R: AsyncRead + Unpin,
+{
+    let kind = reader.read_u8().await?;
+    let channel = reader.read_u16().await?;
+    let size = reader.read_u32().await?;
+    if size > FRAME_MAX {
+        return Err(malformed());
+    }
+    let mut payload = vec![0; size as usize];
+    reader.read_exact(&mut payload).await?;
+    if reader.read_u8().await? != FRAME_END {
+        return Err(malformed());
+    }
+    Ok(Frame {
+        kind,
+        channel,
+        payload,
+    })
+}
+
+/// Encodes a frame.
+pub(super) fn frame(kind: u8, channel: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + FRAME_OVERHEAD as usize);
+    frame.push(kind);
+    frame.extend_from_slice(&channel.to_be_bytes());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame.push(FRAME_END);
+    frame
+}
+
+/// Encodes a heartbeat frame.
+pub(super) fn heartbeat() -> Vec<u8> {
+    frame(FRAME_HEARTBEAT, 0, &[])
+}
+
+/// Encodes the content header and body frames of a message.
+///
+/// `properties` are the property flags and property list exactly as the
+/// publisher sent them.
+pub(super) fn content(channel: u16, frame_max: u32, properties: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(12 + properties.len());
+    header.extend_from_slice(&CLASS_BASIC.to_be_bytes());
+    header.extend_from_slice(&0_u16.to_be_bytes());
+    header.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    header.extend_from_slice(properties);
+    let mut frames = frame(FRAME_HEADER, channel, &header);
+    let frame_max = if frame_max == 0 {
+        FRAME_MAX
+    } else {
+        frame_max.min(FRAME_MAX)
+    };
+    for chunk in body.chunks((frame_max - FRAME_OVERHEAD) as usize) {
+        frames.extend(frame(FRAME_BODY, channel, chunk));
+    }
+    frames
+}
+
+/// Returns whether bit `index` of a packed octet is set.
+pub(super) const fn bit(flags: u8, index: u8) -> bool {
+    flags & (1 << index) != 0
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed frame")
+}
+
+/// Reader of method arguments and content headers.
+pub(super) struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    pub(super) const fn new(buf: &'a [u8]) -> Self {
+        Self(buf)
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(malformed());
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    pub(super) fn octet(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(super) fn short(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub(super) fn long(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?.try_into().map_err(|_| malformed())?;
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    pub(super) fn longlong(&mut self) -> io::Result<u64> {
+        let bytes = self.take(8)?.try_into().map_err(|_| malformed())?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    pub(super) fn shortstr(&mut self) -> io::Result<String> {
+        let len = self.octet()?;
+        let bytes = self.take(usize::from(len))?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| malformed())
+    }
+
+    pub(super) fn longstr(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.long()?;
+        self.take(len as usize)
+    }
+
+    /// Skips a field table, the server ignores all arguments.
+    pub(super) fn table(&mut self) -> io::Result<()> {
+        self.longstr().map(drop)
+    }
+
+    /// Returns the remaining, undecoded bytes.
+    pub(super) const fn rest(self) -> &'a [u8] {
+        self.0
+    }
+}
+
+/// Value in a field table sent by the server.
+pub(super) enum Value<'a> {
+    Bool(bool),
+    Str(&'a str),
+    Table(Vec<(&'a str, Value<'a>)>),
+}
+
+/// Writer of a method frame.
+pub(super) struct Method(Vec<u8>);
+
+impl Method {
+    pub(super) fn new(class: u16, method: u16) -> Self {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&class.to_be_bytes());
+        buf.extend_from_slice(&method.to_be_bytes());
+        Self(buf)
+    }
+
+    pub(super) fn octet(mut self, value: u8) -> Self {
+        self.0.push(value);
+        self
+    }
+
+    pub(super) fn short(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub(super) fn long(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub(super) fn longlong(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    /// Writes a short string, truncated to 255 bytes.
+    pub(super) fn shortstr(mut self, value: &str) -> Self {
+        let bytes = &value.as_bytes()[..value.len().min(255)];
+        self.0.push(bytes.len() as u8);
+        self.0.extend_from_slice(bytes);
+        self
+    }
+
+    pub(super) fn longstr(mut self, value: &[u8]) -> Self {
+        self.0
+            .extend_from_slice(&(value.len() as u32).to_be_bytes());
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    /// Writes consecutive bits packed into one octet.
+    pub(super) fn bits(mut self, bits: &[bool]) -> Self {
+        let mut octet = 0;
+        for (index, bit) in bits.iter().enumerate() {
+            if *bit {
+                octet |= 1 << index;
+            }
+        }
+        self.0.push(octet);
+        self
+    }
+
+    pub(super) fn table(self, entries: &[(&str, Value<'_>)]) -> Self {
+        self.longstr(&encode_table(entries))
+    }
+
+    /// Encodes this method as a frame on `channel`.
+    pub(super) fn frame(self, channel: u16) -> Vec<u8> {
+        frame(FRAME_METHOD, channel, &self.0)
+    }
+}
+
+fn encode_table(entries: &[(&str, Value<'_>)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (name, value) in entries {
+        buf.push(name.len() as u8);
+        buf.extend_from_slice(name.as_bytes());
+        match value {
+            Value::Bool(value) => {
+                buf.push(b't');
+                buf.push(u8::from(*value));
+            }
+            Value::Str(value) => {
+                buf.push(b'S');
+                buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                buf.extend_from_slice(value.as_bytes());
+            }
+            Value::Table(entries) => {
+                let table = encode_table(entries);
+                buf.push(b'F');
+                buf.extend_from_slice(&(table.len() as u32).to_be_bytes());
+                buf.extend_from_slice(&table);
+            }
+        }
+    }
+    buf
+}
@@ -0,0 +1,221 @@
+//! Stand-in OAuth 2.0 token endpoint.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use crate::{config::decode, credentials::OAuth2Config};
+
+/// Configuration of a [`TokenServer`].
+#[derive(Clone, Debug)]
+pub struct TokenServerConfig {
+    /// Client ID clients must authenticate with.
+    ///
+    /// Default: `deadpool-amqprs`
+    pub client_id: String,
+    /// Client secret clients must authenticate with.
+    ///
+    /// Default: `secret`
+    pub client_secret: String,
+    /// Lifetime of the issued tokens, sent as `expires_in`.
+    ///
+    /// Default: one hour
+    pub expires_in: Option<Duration>,
+}
+
+impl Default for TokenServerConfig {
+    fn default() -> Self {
+        Self {
+            client_id: "deadpool-amqprs".to_owned(),
+            client_secret: "secret".to_owned(),
+            expires_in: Some(Duration::from_secs(3600)),
+        }
+    }
+}
+
+struct Shared {
+    config: TokenServerConfig,
+    access_token: Mutex<String>,
+    issued: AtomicUsize,
+}
+
+/// OAuth 2.0 token endpoint supporting the client credentials grant,
+/// listening on a loopback port.
+///
+/// Every token request returns the same access token until it is changed
+/// with [`TokenServer::set_access_token()`], so it can be used as the
+/// password of a [`TestServer`](super::TestServer).
+///
+/// The server is shut down when it is dropped.
+pub struct TokenServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    task: JoinHandle<()>,
+}
+
+impl TokenServer {
+    /// Starts a server with the default [`TokenServerConfig`] on a free
+    /// port, issuing `access_token`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if binding the port failed.
+    pub async fn start(access_token: &str) -> io::Result<Self> {
+        Self::start_with_config(access_token, TokenServerConfig::default()).await
+    }
+
+    /// Starts a server with the given configuration on a free port, issuing
+    /// `access_token`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if binding the port failed.
+    pub async fn start_with_config(
+        access_token: &str,
+        config: TokenServerConfig,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            config,
+            access_token: Mutex::new(access_token.to_owned()),
+            issued: AtomicUsize::new(0),
+        });
+        let task = tokio::spawn(accept(listener, shared.clone()));
+        Ok(Self { addr, shared, task })
+    }
+
+    /// Returns the URL of the token endpoint.
+    #[must_use]
+    pub fn url(&self) -> String {
+        format!("http://{}/token", self.addr)
+    }
+
+    /// Returns an [`OAuth2Config`] fetching tokens from this server.
+    #[must_use]
+    pub fn oauth2_config(&self) -> OAuth2Config {
+        let config = &self.shared.config;
+        OAuth2Config::new(&self.url(), &config.client_id, &config.client_secret)
+    }
+
+    /// Changes the access token issued from now on, e.g. to simulate a
+    /// rotated signing key.
+    pub fn set_access_token(&self, access_token: &str) {
+        *self.shared.access_token.lock().unwrap() = access_token.to_owned();
+    }
+
+    /// Returns the number of tokens issued so far.
+    #[must_use]
+    pub fn issued_count(&self) -> usize {
+        self.shared.issued.load(Ordering::Relaxed)
+    }
+}
+
+impl std::fmt::Debug for TokenServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenServer")
+            .field("addr", &self.addr)
+            .field("config", &self.shared.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for TokenServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn accept(listener: TcpListener, shared: Arc<Shared>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(respond(stream, shared.clone()));
+    }
+}
+
+/// Answers one request and closes the connection.
+async fn respond(stream: TcpStream, shared: Arc<Shared>) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if stream.read_line(&mut header).await? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await?;
+
+    let (status, body) = if request_line.starts_with("POST ") {
+        token_response(&shared, &String::from_utf8_lossy(&body))
+    } else {
+        ("405 Method Not Allowed", String::new())
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.get_mut().write_all(response.as_bytes()).await?;
+    stream.get_mut().shutdown().await
+}
+
+/// Returns the status and JSON body answering a token request with the
+/// form encoded `body`.
+fn token_response(shared: &Shared, body: &str) -> (&'static str, String) {
+    let form: HashMap<String, String> = body
+        .split('&')
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            let component = |s: &str| decode(&s.replace('+', " "), "form").ok();
+            Some((component(name)?, component(value)?))
+        })
+        .collect();
+    let field = |name: &str| form.get(name).map(String::as_str);
+    if field("grant_type") != Some("client_credentials") {
+        return ("400 Bad Request", error("unsupported_grant_type"));
+    }
+    let config = &shared.config;
+    if field("client_id") != Some(config.client_id.as_str())
+        || field("client_secret") != Some(config.client_secret.as_str())
+    {
+        return ("401 Unauthorized", error("invalid_client"));
+    }
+    shared.issued.fetch_add(1, Ordering::Relaxed);
+    let access_token = escape(&shared.access_token.lock().unwrap());
+    let body = match config.expires_in {
+        Some(expires_in) => format!(
+            r#"{{"access_token":"{access_token}","token_type":"bearer","expires_in":{}}}"#,
+            expires_in.as_secs()
+        ),
+        None => format!(r#"{{"access_token":"{access_token}","token_type":"bearer"}}"#),
+    };
+    ("200 OK", body)
+}
+
+fn error(code: &str) -> String {
+    format!(r#"{{"error":"{code}"}}"#)
+}
+
+/// Escapes `s` for use in a JSON string.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,357 @@
+//! Exchanges, queues and bindings declared on every new connection.
+//!
+//! Attach a [`Topology`] to [`Config::topology`](crate::Config::topology) and
+//! the [`Manager`](crate::Manager) declares it on each connection it creates,
+//! so it is restored after every reconnect. Declarations are idempotent as
+//! long as the same entity is always declared with the same properties.
+//!
+//! # Example
+//!
+//! ```rs
+//! use deadpool_amqprs::topology::{Exchange, Queue, QueueBinding, Topology};
+//!
+//! let topology = Topology {
+//!     exchanges: vec![Exchange::new("orders", "topic")],
+//!     queues: vec![Queue::quorum("orders.created")],
+//!     bindings: vec![QueueBinding::new("orders.created", "orders", "order.created")],
+//!     ..Topology::default()
+//! };
+//! ```
+
+use std::collections::BTreeMap;
+
+use amqprs::{
+    channel::{
+        Channel, ExchangeBindArguments, ExchangeDeclareArguments, QueueBindArguments,
+        QueueDeclareArguments,
+    },
+    connection::Connection,
+    FieldName, FieldTable, FieldValue, LongStr,
+};
+
+/// Optional arguments of a declaration, e.g. `x-message-ttl`.
+pub type Arguments = BTreeMap<String, ArgumentValue>;
+
+/// Value of an entry in [`Arguments`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(untagged))]
+pub enum ArgumentValue {
+    /// Boolean value.
+    Bool(bool),
+    /// Integer value, e.g. a TTL in milliseconds.
+    Int(i64),
+    /// String value, e.g. a queue type.
+    String(String),
+}
+
+impl From<bool> for ArgumentValue {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<i64> for ArgumentValue {
+    fn from(value: i64) -> Self {
+        Self::Int(value)
+    }
+}
+
+impl From<&str> for ArgumentValue {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_owned())
+    }
+}
+
+impl From<String> for ArgumentValue {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+/// Exchange to declare.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct Exchange {
+    /// Name of the exchange.
+    pub name: String,
+    /// Type of the exchange, e.g. `direct`, `fanout`, `topic` or `headers`.
+    #[cfg_attr(feature = "serde", serde(rename = "type"))]
+    pub kind: String,
+    /// Whether the exchange survives a broker restart.
+    ///
+    /// Default: `true`
+    #[cfg_attr(feature = "serde", serde(default = "default_true"))]
+    pub durable: bool,
+    /// Whether the exchange is deleted once its last binding is removed.
+    ///
+    /// Default: `false`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub auto_delete: bool,
+    /// Whether the exchange can only be published to by other exchanges.
+    ///
+    /// Default: `false`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub internal: bool,
+    /// Optional arguments, e.g. `alternate-exchange`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub arguments: Arguments,
+}
+
+impl Exchange {
+    /// Creates a durable exchange of the given type.
+    #[must_use]
+    pub fn new(name: &str, kind: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            kind: kind.to_owned(),
+            durable: true,
+            auto_delete: false,
+            internal: false,
+            arguments: Arguments::new(),
+        }
+    }
+
+    /// Adds an optional argument.
+    #[must_use]
+    pub fn with_argument(mut self, name: &str, value: impl Into<ArgumentValue>) -> Self {
+        self.arguments.insert(name.to_owned(), value.into());
+        self
+    }
+}
+
+/// Queue to declare.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct Queue {
+    /// Name of the queue.
+    pub name: String,
+    /// Whether the queue survives a broker restart.
+    ///
+    /// Default: `true`
+    #[cfg_attr(feature = "serde", serde(default = "default_true"))]
+    pub durable: bool,
+    /// Whether the queue is used by the declaring connection only and deleted
+    /// once it closes.
+    ///
+    /// Default: `false`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub exclusive: bool,
+    /// Whether the queue is deleted once its last consumer unsubscribes.
+    ///
+    /// Default: `false`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub auto_delete: bool,
+    /// Optional arguments, e.g. `x-queue-type` or `x-message-ttl`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub arguments: Arguments,
+}
+
+impl Queue {
+    /// Creates a durable classic queue.
+    #[must_use]
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            durable: true,
+            exclusive: false,
+            auto_delete: false,
+            arguments: Arguments::new(),
+        }
+    }
+
+    /// Creates a quorum queue.
+    #[must_use]
+    pub fn quorum(name: &str) -> Self {
+        Self::new(name).with_argument("x-queue-type", "quorum")
+    }
+
+    /// Creates a stream.
+    #[must_use]
+    pub fn stream(name: &str) -> Self {
+        Self::new(name).with_argument("x-queue-type", "stream")
+    }
+
+    /// Adds an optional argument.
+    #[must_use]
+    pub fn with_argument(mut self, name: &str, value: impl Into<ArgumentValue>) -> Self {
+        self.arguments.insert(name.to_owned(), value.into());
+        self
+    }
+}
+
+/// Binding of a queue to an exchange.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct QueueBinding {
+    /// Name of the bound queue.
+    pub queue: String,
+    /// Name of the exchange the queue is bound to.
+    pub exchange: String,
+    /// Routing key of the binding.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub routing_key: String,
+    /// Optional arguments, e.g. the headers matched by a `headers` exchange.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub arguments: Arguments,
+}
+
+impl QueueBinding {
+    /// Creates a binding without arguments.
+    #[must_use]
+    pub fn new(queue: &str, exchange: &str, routing_key: &str) -> Self {
+        Self {
+            queue: queue.to_owned(),
+            exchange: exchange.to_owned(),
+            routing_key: routing_key.to_owned(),
+            arguments: Arguments::new(),
+        }
+    }
+}
+
+/// Binding of an exchange to another exchange.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct ExchangeBinding {
+    /// Name of the exchange messages are routed to.
+    pub destination: String,
+    /// Name of the exchange messages are routed from.
+    pub source: String,
+    /// Routing key of the binding.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub routing_key: String,
+    /// Optional arguments, e.g. the headers matched by a `headers` exchange.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub arguments: Arguments,
+}
+
+impl ExchangeBinding {
+    /// Creates a binding without arguments.
+    #[must_use]
+    pub fn new(destination: &str, source: &str, routing_key: &str) -> Self {
+        Self {
+            destination: destination.to_owned(),
+            source: source.to_owned(),
+            routing_key: routing_key.to_owned(),
+            arguments: Arguments::new(),
+        }
+    }
+}
+
+/// Exchanges, queues and bindings declared on every new connection.
+///
+/// Exchanges are declared first, followed by queues, queue bindings and
+/// exchange bindings.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Topology {
+    /// Exchanges to declare.
+    pub exchanges: Vec<Exchange>,
+    /// Queues to declare.
+    pub queues: Vec<Queue>,
+    /// Bindings of queues to exchanges.
+    pub bindings: Vec<QueueBinding>,
+    /// Bindings of exchanges to exchanges.
+    pub exchange_bindings: Vec<ExchangeBinding>,
+}
+
+impl Topology {
+    /// Returns whether there is nothing to declare.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.exchanges.is_empty()
+            && self.queues.is_empty()
+            && self.bindings.is_empty()
+            && self.exchange_bindings.is_empty()
+    }
+
+    /// Declares this topology on a temporary channel of `conn`.
+    pub(crate) async fn apply(&self, conn: &Connection) -> Result<(), amqprs::error::Error> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let channel = conn.open_channel(None).await?;
+        let declared = self.declare(&channel).await;
+        let closed = channel.close().await;
+        declared.and(closed)
+    }
+
+    async fn declare(&self, channel: &Channel) -> Result<(), amqprs::error::Error> {
+        for exchange in &self.exchanges {
+            channel
+                .exchange_declare(
+                    ExchangeDeclareArguments::new(&exchange.name, &exchange.kind)
+                        .durable(exchange.durable)
+                        .auto_delete(exchange.auto_delete)
+                        .internal(exchange.internal)
+                        .arguments(field_table(&exchange.arguments)?)
+                        .finish(),
+                )
+                .await?;
+        }
+        for queue in &self.queues {
+            channel
+                .queue_declare(
+                    QueueDeclareArguments::new(&queue.name)
+                        .durable(queue.durable)
+                        .exclusive(queue.exclusive)
+                        .auto_delete(queue.auto_delete)
+                        .arguments(field_table(&queue.arguments)?)
+                        .finish(),
+                )
+                .await?;
+        }
+        for binding in &self.bindings {
+            channel
+                .queue_bind(
+                    QueueBindArguments::new(
+                        &binding.queue,
+                        &binding.exchange,
+                        &binding.routing_key,
+                    )
+                    .arguments(field_table(&binding.arguments)?)
+                    .finish(),
+                )
+                .await?;
+        }
+        for binding in &self.exchange_bindings {
+            channel
+                .exchange_bind(
+                    ExchangeBindArguments::new(
+                        &binding.destination,
+                        &binding.source,
+                        &binding.routing_key,
+                    )
+                    .arguments(field_table(&binding.arguments)?)
+                    .finish(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+fn field_table(arguments: &Arguments) -> Result<FieldTable, amqprs::error::Error> {
+    let mut table = FieldTable::new();
+    for (name, value) in arguments {
+        let invalid =
+            || amqprs::error::Error::ChannelUseError(format!("invalid argument `{name}`"));
+        let key: FieldName = name.clone().try_into().map_err(|_| invalid())?;
+        let value = match value {
+            ArgumentValue::Bool(value) => FieldValue::t(*value),
+            ArgumentValue::Int(value) => FieldValue::l(*value),
+            ArgumentValue::String(value) => {
+                let value: LongStr = value.clone().try_into().map_err(|_| invalid())?;
+                FieldValue::S(value)
+            }
+        };
+        table.insert(key, value);
+    }
+    Ok(table)
+}
+
+#[cfg(feature = "serde")]
+const fn default_true() -> bool {
+    true
+}
@@ -0,0 +1,141 @@
+//! Keeping a minimum number of connections open.
+
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+
+use crate::{Pool, PoolError, Timeouts};
+
+/// Interval at which the number of connections is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest time to wait before trying again after opening connections
+/// failed.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Spawns a task opening connections until `pool` holds at least `min_idle`
+/// connections, idle or in use, see
+/// [`Config::min_idle`](crate::Config::min_idle).
+///
+/// The task checks the pool every second, so connections discarded or
+/// evicted are replaced shortly after. deadpool hands out idle connections
+/// before creating new ones, so while connections are missing the task checks
+/// out the idle ones as well. Recycling them counts towards
+/// [`RecyclePolicy::max_uses`](crate::RecyclePolicy::max_uses), restarts
+/// [`RecyclePolicy::max_since_checkout`](crate::RecyclePolicy::max_since_checkout)
+/// and runs the probe of
+/// [`RecyclingMethod::Verified`](crate::RecyclingMethod::Verified).
+/// Once the pool holds enough connections they are left alone. The task never
+/// grows the pool beyond its maximum size and doesn't wait for connections in
+/// use. If opening a connection fails, the time until the next attempt
+/// doubles up to 30 seconds.
+///
+/// The task holds a clone of `pool` and only stops once the pool is closed,
+/// so the pool isn't freed before [`Pool::close()`](deadpool::managed::Pool::close)
+/// is called, even if the returned [`JoinHandle`] is dropped.
+///
+/// # Panics
+///
+/// Panics if called outside of a tokio runtime.
+pub fn spawn_warmup_task(pool: &Pool, min_idle: usize) -> JoinHandle<()> {
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        let mut backoff = CHECK_INTERVAL;
+        while !pool.is_closed() {
+            let delay = match top_up(&pool, min_idle).await {
+                Ok(()) => {
+                    backoff = CHECK_INTERVAL;
+                    CHECK_INTERVAL
+                }
+                #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+                Err(e) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(error = %e, retry_in = ?backoff, "warming up pool failed");
+                    let delay = backoff;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    delay
+                }
+            };
+            tokio::time::sleep(delay).await;
+        }
+    })
+}
+
+/// Opens connections until `pool` holds `min_idle` of them or is full.
+///
+/// deadpool hands out idle connections before creating new ones, so if
+/// connections are missing the idle ones are checked out as well and all of
+/// them are returned at once.
+async fn top_up(pool: &Pool, min_idle: usize) -> Result<(), PoolError> {
+    let status = pool.status();
+    let missing = min_idle.min(status.max_size).saturating_sub(status.size);
+    if missing == 0 {
+        return Ok(());
+    }
+    let timeouts = Timeouts {
+        wait: Some(Duration::ZERO),
+        ..pool.timeouts()
+    };
+    let mut checked_out = Vec::with_capacity(status.available + missing);
+    for _ in 0..status.available + missing {
+        match pool.timeout_get(&timeouts).await {
+            Ok(conn) => checked_out.push(conn),
+            // All permits are taken by connections in use.
+            Err(PoolError::Timeout(_)) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use amqprs::connection::{Connection, OpenConnectionArguments};
+    use deadpool::async_trait;
+
+    use super::*;
+    use crate::{ConnectionFactory, Manager, RecyclingMethod};
+
+    /// [`ConnectionFactory`] failing every attempt and counting them.
+    #[derive(Default)]
+    struct FailingFactory(AtomicUsize);
+
+    #[async_trait]
+    impl ConnectionFactory for FailingFactory {
+        async fn connect(
+            &self,
+            _: &OpenConnectionArguments,
+        ) -> Result<Connection, amqprs::error::Error> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Err(amqprs::error::Error::NetworkError("refused".to_owned()))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_attempts_back_off() {
+        let factory = Arc::new(FailingFactory::default());
+        let manager = Manager::new(
+            OpenConnectionArguments::new("localhost", 5672, "guest", "guest"),
+            RecyclingMethod::Fast,
+        )
+        .with_connection_factory(Arc::clone(&factory) as Arc<dyn ConnectionFactory>);
+        let pool = Pool::builder(manager).max_size(2).build().unwrap();
+        let task = spawn_warmup_task(&pool, 2);
+
+        // Attempts after 0, 1, 3, 7 and 15 seconds.
+        tokio::time::sleep(Duration::from_millis(30_500)).await;
+        assert_eq!(factory.0.load(Ordering::SeqCst), 5);
+
+        // Then 31, 61 and 91 seconds, as the backoff is capped at 30 seconds.
+        tokio::time::sleep(Duration::from_secs(70)).await;
+        assert_eq!(factory.0.load(Ordering::SeqCst), 8);
+
+        pool.close();
+        task.await.unwrap();
+    }
+}
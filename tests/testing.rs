@@ -0,0 +1,582 @@
+//! Tests of the in-process broker of the `testing` feature.
+
+#![cfg(feature = "testing")]
+
+use std::{pin::Pin, time::Duration};
+
+use amqprs::{
+    channel::{
+        BasicAckArguments, BasicConsumeArguments, BasicGetArguments, BasicPublishArguments,
+        QueueDeclareArguments,
+    },
+    BasicProperties,
+};
+use deadpool::managed::{Manager as _, RecycleError};
+use deadpool_amqprs::{
+    channel::ChannelObject,
+    config::RecyclingMethod,
+    consumer::{ConsumerError, ConsumerEvent, ConsumerStream, Delivery},
+    spawn_eviction_task,
+    testing::{TestServer, TestServerConfig},
+    topology::{Exchange, Queue, QueueBinding, Topology},
+    ChannelConfig, Config, ConsumerConfig, Error, Object, PoolConfig, PoolError, PoolExt,
+};
+use futures_core::Stream;
+
+/// Starts a server accepting `user`/`secret` on the `orders` vhost.
+async fn start_server() -> TestServer {
+    TestServer::start_with_config(TestServerConfig {
+        username: "user".to_owned(),
+        password: "secret".to_owned(),
+        vhosts: vec!["orders".to_owned()],
+    })
+    .await
+    .unwrap()
+}
+
+/// Waits until `condition` holds, failing the test after five seconds.
+async fn wait_until(mut condition: impl FnMut() -> bool) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !condition() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("condition wasn't met in time");
+}
+
+#[tokio::test]
+async fn completes_handshake() {
+    let server = start_server().await;
+    let pool = Config::from_url(&server.url()).unwrap().create_pool();
+
+    let conn = pool.get().await.unwrap();
+    assert!(conn.is_open());
+    assert_eq!(server.connection_count(), 1);
+
+    let channel = conn.open_channel(None).await.unwrap();
+    let (queue, _, _) = channel
+        .queue_declare(QueueDeclareArguments::new("orders"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(queue, "orders");
+    assert_eq!(server.queue_len("orders"), Some(0));
+}
+
+#[tokio::test]
+async fn refuses_wrong_credentials() {
+    let server = start_server().await;
+    let url = format!("amqp://user:wrong@{}/orders", server.addr());
+    let pool = Config::from_url(&url).unwrap().create_pool();
+
+    match pool.get().await {
+        Err(PoolError::Backend(Error::AuthenticationFailed(message))) => {
+            assert!(message.contains("ACCESS_REFUSED"), "{message}");
+        }
+        other => panic!("expected refused credentials, got {other:?}"),
+    }
+    assert_eq!(server.connection_count(), 0);
+}
+
+#[tokio::test]
+async fn refuses_unknown_vhost() {
+    let server = start_server().await;
+    let url = format!("amqp://user:secret@{}/billing", server.addr());
+    let pool = Config::from_url(&url).unwrap().create_pool();
+
+    match pool.get().await {
+        Err(PoolError::Backend(Error::VhostAccessRefused(message))) => {
+            assert!(message.contains("NOT_ALLOWED"), "{message}");
+        }
+        other => panic!("expected refused vhost, got {other:?}"),
+    }
+    assert_eq!(server.connection_count(), 0);
+}
+
+#[tokio::test]
+async fn close_connections_replaces_pooled_connections() {
+    let server = start_server().await;
+    let pool = Config::from_url(&server.url()).unwrap().create_pool();
+
+    let conn = pool.get().await.unwrap();
+    server.close_connections(320, "CONNECTION_FORCED - broker forced connection closure");
+    wait_until(|| conn.state().server_close().is_some()).await;
+    let close = conn.state().server_close().unwrap();
+    assert_eq!(close.reply_code, 320);
+    assert_eq!(server.connection_count(), 0);
+    drop(conn);
+
+    let conn = pool.get().await.unwrap();
+    assert!(conn.is_open());
+    assert!(conn.state().server_close().is_none());
+    assert_eq!(server.connection_count(), 1);
+}
+
+#[tokio::test]
+async fn confirms_published_messages() {
+    let server = start_server().await;
+    let pool = Config::from_url(&server.url()).unwrap().create_pool();
+    let conn = pool.get().await.unwrap();
+    let channel = conn.open_channel(None).await.unwrap();
+    channel
+        .queue_declare(QueueDeclareArguments::new("orders"))
+        .await
+        .unwrap();
+    channel.close().await.unwrap();
+    drop(conn);
+
+    let publishers = ChannelConfig::default().create_publisher_pool(pool);
+    let publisher = publishers.get().await.unwrap();
+    for _ in 0..3 {
+        let confirmation = publisher
+            .publish(
+                BasicPublishArguments::new("", "orders"),
+                BasicProperties::default(),
+                b"{}".to_vec(),
+            )
+            .await
+            .unwrap();
+        assert!(confirmation.is_ack());
+    }
+    assert_eq!(server.queue_len("orders"), Some(3));
+}
+
+#[tokio::test]
+async fn gets_messages() {
+    let server = start_server().await;
+    let pool = Config::from_url(&server.url()).unwrap().create_pool();
+    let conn = pool.get().await.unwrap();
+    let channel = conn.open_channel(None).await.unwrap();
+    channel
+        .queue_declare(QueueDeclareArguments::new("orders"))
+        .await
+        .unwrap();
+    server.publish("", "orders", "first");
+    server.publish("", "orders", "second");
+    assert_eq!(server.queue_len("orders"), Some(2));
+
+    let (_, _, body) = channel
+        .basic_get(BasicGetArguments::new("orders").no_ack(true).finish())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(body, b"first");
+    assert_eq!(server.queue_len("orders"), Some(1));
+
+    channel
+        .basic_get(BasicGetArguments::new("orders").no_ack(true).finish())
+        .await
+        .unwrap()
+        .unwrap();
+    let empty = channel
+        .basic_get(BasicGetArguments::new("orders"))
+        .await
+        .unwrap();
+    assert!(empty.is_none());
+}
+
+#[tokio::test]
+async fn consumes_and_acks_messages() {
+    let server = start_server().await;
+    let pool = Config::from_url(&server.url()).unwrap().create_pool();
+    let conn = pool.get().await.unwrap();
+    let channel = conn.open_channel(None).await.unwrap();
+    channel
+        .queue_declare(QueueDeclareArguments::new("orders"))
+        .await
+        .unwrap();
+    let (_, mut messages) = channel
+        .basic_consume_rx(BasicConsumeArguments::new("orders", "test"))
+        .await
+        .unwrap();
+    assert_eq!(server.consumer_count("orders"), Some(1));
+
+    server.publish("", "orders", "first");
+    server.publish("", "orders", "second");
+    for expected in [&b"first"[..], b"second"] {
+        let message = messages.recv().await.unwrap();
+        assert_eq!(message.content.as_deref(), Some(expected));
+        let delivery_tag = message.deliver.unwrap().delivery_tag();
+        channel
+            .basic_ack(BasicAckArguments::new(delivery_tag, false))
+            .await
+            .unwrap();
+    }
+
+    // Acknowledged messages aren't requeued when the channel closes.
+    channel.close().await.unwrap();
+    wait_until(|| server.consumer_count("orders") == Some(0)).await;
+    assert_eq!(server.queue_len("orders"), Some(0));
+}
+
+#[tokio::test]
+async fn unacked_messages_are_requeued() {
+    let server = start_server().await;
+    let pool = Config::from_url(&server.url()).unwrap().create_pool();
+    let conn = pool.get().await.unwrap();
+    let channel = conn.open_channel(None).await.unwrap();
+    channel
+        .queue_declare(QueueDeclareArguments::new("orders"))
+        .await
+        .unwrap();
+    let (_, mut messages) = channel
+        .basic_consume_rx(BasicConsumeArguments::new("orders", "test"))
+        .await
+        .unwrap();
+    server.publish("", "orders", "first");
+    messages.recv().await.unwrap();
+    assert_eq!(server.queue_len("orders"), Some(0));
+
+    channel.close().await.unwrap();
+    wait_until(|| server.queue_len("orders") == Some(1)).await;
+}
+
+#[tokio::test]
+async fn blocks_and_unblocks_connections() {
+    let server = start_server().await;
+    let pool = Config::from_url(&server.url()).unwrap().create_pool();
+    let conn = pool.get().await.unwrap();
+
+    server.block_connections("low on memory");
+    wait_until(|| conn.state().is_blocked()).await;
+    assert_eq!(
+        conn.state().blocked_reason().as_deref(),
+        Some("low on memory")
+    );
+
+    server.unblock_connections();
+    wait_until(|| !conn.state().is_blocked()).await;
+}
+
+#[tokio::test]
+async fn warmup_opens_and_replaces_connections() {
+    let server = start_server().await;
+    let mut config = Config::from_url(&server.url()).unwrap();
+    config.min_idle = 2;
+    let pool = config.create_pool();
+
+    wait_until(|| server.connection_count() == 2).await;
+    assert_eq!(pool.status().size, 2);
+    assert_eq!(pool.status().available, 2);
+
+    spawn_eviction_task(&pool, Duration::from_millis(50));
+    server.close_connections(320, "CONNECTION_FORCED - broker forced connection closure");
+    wait_until(|| server.connection_count() == 0).await;
+    wait_until(|| server.connection_count() == 2).await;
+    assert_eq!(pool.status().size, 2);
+
+    pool.close();
+}
+
+#[tokio::test]
+async fn channels_share_connections() {
+    let server = start_server().await;
+    let pool = Config::from_url(&server.url()).unwrap().create_pool();
+    let channels = ChannelConfig::new(2, None).create_pool(pool.clone());
+
+    let first = channels.get().await.unwrap();
+    let second = channels.get().await.unwrap();
+    assert!(std::ptr::eq(first.connection(), second.connection()));
+    assert_ne!(first.channel_id(), second.channel_id());
+    assert_eq!(server.connection_count(), 1);
+
+    let third = channels.get().await.unwrap();
+    assert!(!std::ptr::eq(first.connection(), third.connection()));
+    assert_eq!(server.connection_count(), 2);
+    assert_eq!(pool.status().size, 2);
+    assert_eq!(pool.status().available, 0);
+}
+
+#[tokio::test]
+async fn concurrent_channels_respect_channels_per_connection() {
+    let server = start_server().await;
+    let pool = Config::from_url(&server.url()).unwrap().create_pool();
+    let channels = ChannelConfig::new(3, Some(PoolConfig::new(9))).create_pool(pool);
+
+    let tasks: Vec<_> = (0..9)
+        .map(|_| {
+            let channels = channels.clone();
+            tokio::spawn(async move { channels.get().await.unwrap() })
+        })
+        .collect();
+    let mut opened = Vec::new();
+    for task in tasks {
+        opened.push(task.await.unwrap());
+    }
+
+    for channel in &opened {
+        let sharing = opened
+            .iter()
+            .filter(|other| std::ptr::eq(channel.connection(), other.connection()))
+            .count();
+        assert!(sharing <= 3, "{sharing} channels share a connection");
+    }
+    assert_eq!(server.connection_count(), 3);
+}
+
+#[tokio::test]
+async fn connection_is_returned_after_its_last_channel() {
+    let server = start_server().await;
+    let pool = Config::from_url(&server.url()).unwrap().create_pool();
+    let channels = ChannelConfig::new(2, None).create_pool(pool.clone());
+
+    let first = channels.get().await.unwrap();
+    let second = channels.get().await.unwrap();
+    assert_eq!(pool.status().available, 0);
+
+    // Pooled channels keep their connection, so take them out of the pool.
+    drop(ChannelObject::take(first));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(pool.status().available, 0);
+
+    drop(ChannelObject::take(second));
+    wait_until(|| pool.status().available == 1).await;
+    assert_eq!(server.connection_count(), 1);
+}
+
+/// Returns the next event of `stream`, failing the test after five seconds.
+async fn next_event(stream: &mut ConsumerStream) -> ConsumerEvent {
+    let next = std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx));
+    tokio::time::timeout(Duration::from_secs(5), next)
+        .await
+        .expect("no event in time")
+        .expect("stream ended")
+}
+
+/// Returns the next event of `stream`, which has to be a delivery.
+async fn next_delivery(stream: &mut ConsumerStream) -> Box<Delivery> {
+    match next_event(stream).await {
+        ConsumerEvent::Delivery(delivery) => delivery,
+        event => panic!("expected a delivery, got {event:?}"),
+    }
+}
+
+#[tokio::test]
+async fn consumer_reconnects() {
+    let server = start_server().await;
+    let pool = Config::from_url(&server.url()).unwrap().create_pool();
+    let conn = pool.get().await.unwrap();
+    let channel = conn.open_channel(None).await.unwrap();
+    channel
+        .queue_declare(QueueDeclareArguments::new("orders"))
+        .await
+        .unwrap();
+    channel.close().await.unwrap();
+    drop(conn);
+
+    let mut config = ConsumerConfig::new("orders");
+    config.prefetch_count = 1;
+    config.reconnect_delay = Duration::from_millis(10);
+    let mut deliveries = config.consume(pool);
+    wait_until(|| server.consumer_count("orders") == Some(1)).await;
+
+    server.publish("", "orders", "first");
+    let unacked = next_delivery(&mut deliveries).await;
+    assert_eq!(unacked.content, b"first");
+
+    server.close_connections(320, "CONNECTION_FORCED - broker forced connection closure");
+    assert!(matches!(
+        next_event(&mut deliveries).await,
+        ConsumerEvent::Disconnected(ConsumerError::Closed)
+    ));
+    assert!(matches!(
+        next_event(&mut deliveries).await,
+        ConsumerEvent::Reconnected
+    ));
+    assert_eq!(server.consumer_count("orders"), Some(1));
+    assert!(unacked.ack().await.is_err());
+
+    // The unacknowledged delivery comes again, and the prefetch count applies
+    // to the new channel as well.
+    server.publish("", "orders", "second");
+    let delivery = next_delivery(&mut deliveries).await;
+    assert_eq!(delivery.content, b"first");
+    assert!(delivery.deliver.redelivered());
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(server.queue_len("orders"), Some(1));
+    delivery.ack().await.unwrap();
+    assert_eq!(next_delivery(&mut deliveries).await.content, b"second");
+}
+
+#[tokio::test]
+async fn verified_recycling_releases_probe_channels() {
+    let server = start_server().await;
+    let mut config = Config::from_url(&server.url()).unwrap();
+    config.recycling_method = RecyclingMethod::Verified;
+    let pool = config.create_pool();
+
+    for _ in 0..10 {
+        drop(pool.get().await.unwrap());
+    }
+    let conn = pool.get().await.unwrap();
+    assert_eq!(Object::metrics(&conn).recycle_count, 10);
+    assert_eq!(server.connection_count(), 1);
+    // Probe channels were closed, so their numbers are free again.
+    let channel = conn.open_channel(None).await.unwrap();
+    assert_eq!(channel.channel_id(), 1);
+}
+
+#[tokio::test]
+async fn verified_recycling_times_out() {
+    let server = start_server().await;
+    let mut config = Config::from_url(&server.url()).unwrap();
+    config.recycling_method = RecyclingMethod::Verified;
+    // The probe needs a round-trip, so it never finishes without waiting.
+    config.recycle_timeout = Some(Duration::ZERO);
+    let pool = config.create_pool();
+
+    let mut conn = pool.get().await.unwrap();
+    let metrics = *Object::metrics(&conn);
+    assert!(matches!(
+        pool.manager().recycle(&mut conn, &metrics).await,
+        Err(RecycleError::Backend(Error::ProbeTimedOut))
+    ));
+
+    // The pool replaces connections failing the probe.
+    drop(conn);
+    let conn = pool.get().await.unwrap();
+    assert_eq!(Object::metrics(&conn).recycle_count, 0);
+    assert!(conn.is_usable());
+}
+
+#[tokio::test]
+async fn discarded_connections_are_closed() {
+    let server = start_server().await;
+    let mut config = Config::from_url(&server.url()).unwrap();
+    config.recycle_policy.max_uses = Some(1);
+    let pool = config.create_pool();
+
+    let conn = pool.get().await.unwrap();
+    // Keeps the socket open unless the connection is closed explicitly.
+    let discarded = (**conn).clone();
+    drop(conn);
+
+    let conn = pool.get().await.unwrap();
+    assert_eq!(Object::metrics(&conn).recycle_count, 0);
+    wait_until(|| !discarded.is_open()).await;
+    wait_until(|| server.connection_count() == 1).await;
+}
+
+#[tokio::test]
+async fn shutdown_waits_for_checked_out_connections() {
+    let server = start_server().await;
+    let pool = Config::from_url(&server.url()).unwrap().create_pool();
+    let conn = pool.get().await.unwrap();
+    drop(pool.get().await.unwrap());
+    assert_eq!(server.connection_count(), 2);
+
+    let shutdown = tokio::spawn({
+        let pool = pool.clone();
+        async move { pool.shutdown().await }
+    });
+    // The idle connection is closed right away, the checked out one isn't.
+    wait_until(|| server.connection_count() == 1).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!shutdown.is_finished());
+    assert!(conn.is_open());
+
+    drop(conn);
+    tokio::time::timeout(Duration::from_secs(5), shutdown)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(server.connection_count(), 0);
+}
+
+#[tokio::test]
+async fn failing_custom_check_discards_connections() {
+    let server = start_server().await;
+    let mut config = Config::from_url(&server.url()).unwrap();
+    config.recycling_method =
+        RecyclingMethod::custom(|_, _| Box::pin(async { Err("queue is gone".into()) }));
+    let pool = config.create_pool();
+
+    let mut conn = pool.get().await.unwrap();
+    let metrics = *Object::metrics(&conn);
+    match pool.manager().recycle(&mut conn, &metrics).await {
+        Err(RecycleError::Backend(Error::RecycleCheck(e))) => {
+            assert_eq!(e.to_string(), "queue is gone");
+        }
+        result => panic!("expected a failed check, got {result:?}"),
+    }
+
+    drop(conn);
+    let conn = pool.get().await.unwrap();
+    assert_eq!(Object::metrics(&conn).recycle_count, 0);
+}
+
+#[tokio::test]
+async fn custom_check_is_bounded_by_recycle_timeout() {
+    let server = start_server().await;
+    let mut config = Config::from_url(&server.url()).unwrap();
+    config.recycling_method = RecyclingMethod::custom(|_, _| {
+        Box::pin(async {
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+            Ok(())
+        })
+    });
+    config.recycle_timeout = Some(Duration::from_millis(50));
+    let pool = config.create_pool();
+
+    let mut conn = pool.get().await.unwrap();
+    let metrics = *Object::metrics(&conn);
+    let recycled = tokio::time::timeout(
+        Duration::from_secs(5),
+        pool.manager().recycle(&mut conn, &metrics),
+    )
+    .await
+    .expect("recycle_timeout didn't apply");
+    assert!(matches!(
+        recycled,
+        Err(RecycleError::Backend(Error::ProbeTimedOut))
+    ));
+}
+
+#[tokio::test]
+async fn declares_topology_on_new_connections() {
+    let server = start_server().await;
+    let mut config = Config::from_url(&server.url()).unwrap();
+    config.topology = Some(Topology {
+        exchanges: vec![
+            Exchange::new("orders", "topic").with_argument("alternate-exchange", "orders.unrouted"),
+            Exchange::new("orders.unrouted", "fanout"),
+        ],
+        queues: vec![Queue::new("orders.created"), Queue::new("orders.unrouted")],
+        bindings: vec![
+            QueueBinding::new("orders.created", "orders", "order.created.*"),
+            QueueBinding::new("orders.unrouted", "orders.unrouted", ""),
+        ],
+        ..Topology::default()
+    });
+    let pool = config.create_pool();
+
+    drop(pool.get().await.unwrap());
+    assert!(server.has_exchange("orders"));
+    assert!(server.has_exchange("orders.unrouted"));
+    assert_eq!(server.queue_len("orders.created"), Some(0));
+
+    server.publish("orders", "order.created.eu", "{}");
+    assert_eq!(server.queue_len("orders.created"), Some(1));
+    assert_eq!(server.queue_len("orders.unrouted"), Some(0));
+}
+
+#[tokio::test]
+async fn failed_topology_fails_creating_connections() {
+    let server = start_server().await;
+    let mut config = Config::from_url(&server.url()).unwrap();
+    config.topology = Some(Topology {
+        queues: vec![Queue::new("orders.created")],
+        bindings: vec![QueueBinding::new("orders.created", "missing", "")],
+        ..Topology::default()
+    });
+    let pool = config.create_pool();
+
+    assert!(matches!(
+        pool.get().await,
+        Err(PoolError::Backend(Error::Topology(_)))
+    ));
+    assert_eq!(server.queue_len("orders.created"), Some(0));
+    // The connection the topology failed on is closed again.
+    wait_until(|| server.connection_count() == 0).await;
+}